@@ -0,0 +1,412 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use eframe::egui;
+use egui::{CentralPanel, CollapsingHeader, Color32, ComboBox, Context, DragValue, ProgressBar, Slider, TextEdit, Ui, vec2};
+use z407::config::Theme;
+use z407::schedule::{IfDisconnected, RuleAction};
+use z407::{
+    scheduler, triggers, Command, Config, Easing, Level, LevelKind, Preset, PresetProgress, Rule, Schedule, Speaker, Speakers,
+    SystemClock,
+};
+
+/// A rule as edited, with its action and speaker kept as the text typed in.
+struct RuleEdit {
+    rule: Rule,
+    action: String,
+    speaker: String,
+}
+
+impl RuleEdit {
+    fn new(rule: Rule) -> Self {
+        let action = rule.action().map(|a| a.to_string()).unwrap_or_default();
+        let speaker = rule.speaker.clone().unwrap_or_default();
+        Self { rule, action, speaker }
+    }
+
+    fn to_rule(&self) -> anyhow::Result<Rule> {
+        let mut rule = self.rule.clone();
+        rule.set_action(self.action.parse::<RuleAction>()?);
+        rule.speaker = (!self.speaker.trim().is_empty()).then(|| self.speaker.trim().to_string());
+        Ok(rule)
+    }
+}
+
+struct Z407PuckApp {
+    speakers: Speakers,
+    /// Index of the speaker shown; commands go to every speaker while `all` is set.
+    selected: usize,
+    all: bool,
+    level_drag: Option<(LevelKind, u8)>,
+    presets: Vec<(String, Preset)>,
+    schedule_path: Option<PathBuf>,
+    rules: Vec<RuleEdit>,
+    /// Result of the last load or save.
+    schedule_status: Option<(Color32, String)>,
+    /// How long a fade out takes, in seconds.
+    fade_secs: u64,
+    sleep_minutes: u64,
+    /// When the sleep timer fades the volume out.
+    sleep_at: Option<Instant>,
+    /// Fades out still running, from the button or the sleep timer.
+    fading: Arc<AtomicUsize>,
+}
+
+impl Z407PuckApp {
+    fn new(cc: &eframe::CreationContext<'_>, config: &Config, simulate: bool) -> Self {
+        cc.egui_ctx.set_visuals(match config.ui.theme {
+            Theme::Dark => egui::Visuals::dark(),
+            Theme::Light => egui::Visuals::light(),
+        });
+        let speakers = if simulate {
+            Speakers::simulate(config, &config.speakers())
+        } else {
+            Speakers::spawn(config, &config.speakers())
+        };
+        let presets = config.presets.iter().map(|(name, p)| (name.clone(), p.clone())).collect();
+        let schedule_path = config.scheduler.path();
+        let (rules, schedule_status) = match schedule_path.as_deref().map(Schedule::load) {
+            Some(Ok(schedule)) => (schedule.rules.into_iter().map(RuleEdit::new).collect(), None),
+            Some(Err(e)) => (Vec::new(), Some((Color32::YELLOW, format!("{:#}", e)))),
+            None => (Vec::new(), None),
+        };
+        triggers::spawn(&speakers, &config.triggers);
+        if let (true, Some(path)) = (config.scheduler.enabled, schedule_path.clone()) {
+            speakers.runtime().spawn(scheduler::run(speakers.fleet(), path, SystemClock));
+        }
+        Self {
+            speakers,
+            selected: 0,
+            all: false,
+            level_drag: None,
+            presets,
+            schedule_path,
+            rules,
+            schedule_status,
+            fade_secs: 10,
+            sleep_minutes: 30,
+            sleep_at: None,
+            fading: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn shown(&self) -> &Speaker {
+        self.speakers.iter().nth(self.selected).unwrap()
+    }
+
+    fn targets(&self) -> Vec<&Speaker> {
+        match self.all {
+            true => self.speakers.iter().collect(),
+            false => vec![self.shown()],
+        }
+    }
+
+    fn send_cmd(&self, cmd: Command) { self.targets().iter().for_each(|s| s.conn.send(cmd)); }
+    fn play_pause(&self) { self.send_cmd(Command::PlayPause); }
+    fn next_track(&self) { self.send_cmd(Command::NextTrack); }
+    fn prev_track(&self) { self.send_cmd(Command::PrevTrack); }
+    fn switch_bluetooth(&self) { self.send_cmd(Command::SwitchBluetooth); }
+    fn switch_aux(&self) { self.send_cmd(Command::SwitchAux); }
+    fn switch_usb(&self) { self.send_cmd(Command::SwitchUsb); }
+    fn sound_1(&self) { self.send_cmd(Command::Sound1); }
+    fn sound_2(&self) { self.send_cmd(Command::Sound2); }
+    fn sound_3(&self) { self.send_cmd(Command::Sound3); }
+    fn unknown_1(&self) { self.send_cmd(Command::Unknown1); }
+    fn pairing(&self) { self.send_cmd(Command::Pairing); }
+    fn factory_reset(&self) { self.send_cmd(Command::FactoryReset); }
+
+    fn set_level(&self, kind: LevelKind, target: u8) {
+        for speaker in self.targets() {
+            let (ctl, name) = (speaker.conn.controller(), speaker.name.clone());
+            speaker.conn.runtime().spawn(async move {
+                if let Err(e) = ctl.set_level(kind, target).await {
+                    eprintln!("{}: set {} failed: {}", name, kind, e);
+                }
+            });
+        }
+    }
+
+    fn calibrate(&self, kind: LevelKind) {
+        for speaker in self.targets() {
+            let (ctl, name) = (speaker.conn.controller(), speaker.name.clone());
+            speaker.conn.runtime().spawn(async move {
+                if let Err(e) = ctl.calibrate(kind).await {
+                    eprintln!("{}: {} calibration failed: {}", name, kind, e);
+                }
+            });
+        }
+    }
+
+    fn apply_preset(&self, name: &str, preset: &Preset) {
+        for speaker in self.targets() {
+            let (ctl, who, name, preset) = (speaker.conn.controller(), speaker.name.clone(), name.to_string(), preset.clone());
+            speaker.conn.runtime().spawn(async move {
+                if let Err(e) = ctl.apply_preset(&name, &preset).await {
+                    eprintln!("{}: preset {} failed: {}", who, name, e);
+                }
+            });
+        }
+    }
+
+    fn fade_out(&self) {
+        let over = Duration::from_secs(self.fade_secs);
+        for speaker in self.targets() {
+            let (ctl, name, fading) = (speaker.conn.controller(), speaker.name.clone(), self.fading.clone());
+            fading.fetch_add(1, Ordering::SeqCst);
+            speaker.conn.runtime().spawn(async move {
+                if let Err(e) = ctl.fade_out(over, Easing::default()).await {
+                    eprintln!("{}: fade out failed: {}", name, e);
+                }
+                fading.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    }
+
+    fn cancel(&self) { self.targets().iter().for_each(|s| s.conn.controller().cancel()); }
+
+    fn fade_rows(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| match self.fading.load(Ordering::SeqCst) {
+            0 => {
+                if ui.button("Fade out").clicked() { self.fade_out(); }
+                ui.add(DragValue::new(&mut self.fade_secs).clamp_range(0..=600).suffix(" s"));
+            }
+            _ => {
+                ui.label("Fading out");
+                if ui.button("Cancel").clicked() { self.cancel(); }
+            }
+        });
+        ui.horizontal(|ui| match self.sleep_at {
+            Some(at) => {
+                let left = at.saturating_duration_since(Instant::now()).as_secs();
+                ui.label(format!("Fading out in {}:{:02}", left / 60, left % 60));
+                if ui.button("Cancel").clicked() { self.sleep_at = None; }
+            }
+            None => {
+                if ui.button("Sleep in").clicked() {
+                    self.sleep_at = Some(Instant::now() + Duration::from_secs(self.sleep_minutes * 60));
+                }
+                ui.add(DragValue::new(&mut self.sleep_minutes).clamp_range(1..=240).suffix(" min"));
+            }
+        });
+    }
+
+    fn preset_rows(&self, ui: &mut Ui, progress: Option<&PresetProgress>) {
+        if let Some(p) = progress {
+            ui.horizontal(|ui| {
+                let fraction = p.done as f32 / p.total.max(1) as f32;
+                ui.add(ProgressBar::new(fraction).desired_width(200.0).text(format!("{} {}/{}", p.name, p.done, p.total)));
+                if ui.button("Cancel").clicked() { self.cancel(); }
+            });
+        } else if !self.presets.is_empty() {
+            ui.horizontal_wrapped(|ui| {
+                for (name, preset) in &self.presets {
+                    if ui.button(name).on_hover_text(preset.to_string()).clicked() { self.apply_preset(name, preset); }
+                }
+            });
+        }
+    }
+
+    fn save_schedule(&mut self) {
+        let Some(path) = &self.schedule_path else { return };
+        let rules: anyhow::Result<Vec<Rule>> = self.rules.iter().map(RuleEdit::to_rule).collect();
+        let saved = rules.and_then(|rules| Schedule { rules }.save(path));
+        self.schedule_status = Some(match saved {
+            Ok(()) => (Color32::GREEN, format!("Saved to {}", path.display())),
+            Err(e) => (Color32::YELLOW, format!("{:#}", e)),
+        });
+    }
+
+    fn schedule_rows(&mut self, ui: &mut Ui) {
+        let mut remove = None;
+        let now = chrono::Local::now();
+        for (i, edit) in self.rules.iter_mut().enumerate() {
+            ui.push_id(i, |ui| {
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut edit.rule.enabled, "");
+                    ui.add(TextEdit::singleline(&mut edit.rule.name).hint_text("name").desired_width(80.0));
+                    let next = edit.rule.next_after(now).map(|at| at.format("next %a %H:%M").to_string());
+                    ui.add(TextEdit::singleline(&mut edit.rule.cron).hint_text("0 22 * * *").desired_width(90.0))
+                        .on_hover_text(next.unwrap_or_else(|| "invalid cron expression".to_string()));
+                    if ui.button("🗑").clicked() { remove = Some(i); }
+                });
+                ui.horizontal(|ui| {
+                    ui.add(TextEdit::singleline(&mut edit.action).hint_text("switch_aux / preset quiet").desired_width(130.0));
+                    ui.add(TextEdit::singleline(&mut edit.speaker).hint_text("all").desired_width(60.0));
+                    ComboBox::from_id_source("if_disconnected")
+                        .selected_text(edit.rule.if_disconnected.to_string())
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(&mut edit.rule.if_disconnected, IfDisconnected::Queue, "queue");
+                            ui.selectable_value(&mut edit.rule.if_disconnected, IfDisconnected::Skip, "skip");
+                        });
+                });
+            });
+        }
+        if let Some(i) = remove { self.rules.remove(i); }
+        ui.horizontal(|ui| {
+            if ui.button("Add").clicked() {
+                let rule = Rule {
+                    name: format!("rule {}", self.rules.len() + 1),
+                    cron: "0 22 * * *".to_string(),
+                    command: Some(Command::VolumeDown),
+                    steps: None,
+                    preset: None,
+                    speaker: None,
+                    if_disconnected: IfDisconnected::default(),
+                    enabled: true,
+                };
+                self.rules.push(RuleEdit::new(rule));
+            }
+            if ui.button("Save").clicked() { self.save_schedule(); }
+        });
+        if let Some((color, status)) = &self.schedule_status {
+            ui.colored_label(*color, status);
+        }
+    }
+
+    fn speaker_row(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            for (i, speaker) in self.speakers.iter().enumerate() {
+                let connected = speaker.conn.snapshot().connected;
+                let color = if connected { Color32::GREEN } else { Color32::RED };
+                let label = egui::RichText::new(&speaker.name).color(color);
+                if ui.selectable_label(i == self.selected, label).clicked() { self.selected = i; }
+            }
+            ui.checkbox(&mut self.all, "All");
+        });
+    }
+
+    fn level_row(&mut self, ui: &mut Ui, kind: LevelKind, level: Level) {
+        ui.horizontal(|ui| {
+            if ui.button(format!("{} -", kind)).clicked() { self.send_cmd(kind.down()); }
+            let dragged = self.level_drag.filter(|(k, _)| *k == kind).map(|(_, v)| v);
+            let mut value = dragged.or(level.steps).unwrap_or(0).min(level.ceiling());
+            let text = match level.limit {
+                Some(limit) => format!("{} (max {})", kind, limit),
+                None => kind.to_string(),
+            };
+            let slider = ui.add(Slider::new(&mut value, 0..=level.ceiling()).text(text));
+            if slider.dragged() {
+                self.level_drag = Some((kind, value));
+            }
+            if slider.drag_stopped() || (slider.changed() && !slider.dragged()) {
+                self.level_drag = None;
+                self.set_level(kind, value);
+            }
+            let raise = level.check_raise(kind);
+            let up = ui.add_enabled(raise.is_ok(), egui::Button::new(format!("{} +", kind)));
+            if let Err(e) = raise { up.on_disabled_hover_text(e.to_string()); } else if up.clicked() { self.send_cmd(kind.up()); }
+        });
+        if !level.is_calibrated() {
+            ui.horizontal(|ui| {
+                ui.label(format!("{} level unknown", kind));
+                if ui.button("Calibrate").clicked() { self.calibrate(kind); }
+            });
+        }
+    }
+}
+
+impl eframe::App for Z407PuckApp {
+    fn update(&mut self, ctx: &Context, _frame: &mut eframe::Frame) {
+        self.speakers.responses();
+        if self.sleep_at.is_some_and(|at| Instant::now() >= at) {
+            self.sleep_at = None;
+            self.fade_out();
+        }
+
+        let current_state = self.shown().conn.snapshot();
+
+        CentralPanel::default().show(ctx, |ui| {
+            ui.with_layout(egui::Layout::top_down(egui::Align::Center), |ui| {
+                ui.heading("Z407 Digital Puck");
+                if self.speakers.len() > 1 {
+                    self.speaker_row(ui);
+                }
+                ui.add_space(10.0);
+
+                if !current_state.connected {
+                    if ui.button("Scan & Connect").clicked() {
+                        self.targets().iter().for_each(|s| s.conn.request_scan());
+                    }
+                } else {
+                    self.level_row(ui, LevelKind::Volume, current_state.volume);
+                    self.level_row(ui, LevelKind::Bass, current_state.bass);
+                    self.preset_rows(ui, current_state.preset.as_ref());
+                    self.fade_rows(ui);
+                    ui.add_space(5.0);
+                    let input = current_state.current_input.map(|i| i.to_string()).unwrap_or_default();
+                    ui.label(format!("Current Input: {}", input));
+                    let last = current_state.last_response.as_ref().map(|r| format!("{:?}", r)).unwrap_or_default();
+                    ui.label(format!("Last Response: {}", last));
+                    ui.horizontal(|ui| {
+                        if ui.button("⏮️").clicked() { self.prev_track(); }
+                        if ui.button("⏯️").clicked() { self.play_pause(); }
+                        if ui.button("⏭️").clicked() { self.next_track(); }
+                    });
+                    ui.horizontal(|ui| {
+                        if ui.button("BT").clicked() { self.switch_bluetooth(); }
+                        if ui.button("AUX").clicked() { self.switch_aux(); }
+                        if ui.button("USB").clicked() { self.switch_usb(); }
+                    });
+                    ui.horizontal(|ui| {
+                        if ui.button("Chime 1").clicked() { self.sound_1(); }
+                        if ui.button("Chime 2").clicked() { self.sound_2(); }
+                        if ui.button("Chime 3").clicked() { self.sound_3(); }
+                        if ui.button("Unknown").clicked() { self.unknown_1(); }
+                    });
+                    ui.add_space(5.0);
+                    ui.horizontal(|ui| {
+                        if ui.button("Pairing Mode").clicked() { self.pairing(); }
+                        if ui.button("Factory Reset").clicked() { self.factory_reset(); }
+                    });
+                }
+
+                if self.schedule_path.is_some() {
+                    CollapsingHeader::new("Schedule").show(ui, |ui| self.schedule_rows(ui));
+                }
+
+                ui.add_space(10.0);
+                let (color, text) = if current_state.connected {
+                    (Color32::GREEN, "Connected to Z407")
+                } else {
+                    (Color32::RED, "Disconnected - Click to Scan")
+                };
+                ui.colored_label(color, text);
+                if let (false, Some(retry)) = (current_state.connected, &current_state.retry) {
+                    let secs = retry.retry_at.saturating_duration_since(Instant::now()).as_secs();
+                    ui.label(format!("Reconnecting in {}s (attempt {})", secs, retry.attempt));
+                }
+                if let Some(err) = &current_state.last_error {
+                    ui.colored_label(Color32::YELLOW, err);
+                }
+            });
+        });
+
+        ctx.request_repaint_after(Duration::from_millis(50));
+    }
+}
+
+fn main() -> Result<(), eframe::Error> {
+    env_logger::init();
+    let args: Vec<String> = std::env::args().collect();
+    let simulate = args.iter().any(|a| a == "--simulate");
+    let config_path = args.iter().position(|a| a == "--config").and_then(|i| args.get(i + 1));
+    let config = match Config::load(config_path.map(Path::new)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            std::process::exit(2);
+        }
+    };
+    let mut viewport = egui::ViewportBuilder::default().with_inner_size(vec2(config.ui.width, config.ui.height));
+    if config.ui.always_on_top {
+        viewport = viewport.with_always_on_top();
+    }
+    let options = eframe::NativeOptions { viewport, ..Default::default() };
+    eframe::run_native(
+        "Z407 Puck",
+        options,
+        Box::new(move |cc| Box::new(Z407PuckApp::new(cc, &config, simulate))),
+    )
+}
\ No newline at end of file
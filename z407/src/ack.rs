@@ -0,0 +1,187 @@
+use std::fmt;
+use std::time::Duration;
+
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::oneshot;
+use tokio::time::{timeout_at, Instant};
+
+use crate::level::LevelKind;
+use crate::protocol::{Command, Response};
+use crate::transport::Z407Transport;
+
+/// Confirmations carry no id, so a `c002` from twisting the puck while `VolumeUp` waits is taken
+/// as its confirmation.
+#[derive(Debug, Clone)]
+pub struct AckPolicy {
+    pub timeout: Duration,
+    /// Rewrites of an unconfirmed command. Volume and bass steps are never rewritten: a late
+    /// confirmation would mean the first write landed, and the level would move twice.
+    pub max_retries: u32,
+}
+
+impl Default for AckPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(1),
+            max_retries: 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    /// No confirmation arrived after the initial write and every retry.
+    Timeout { attempts: u32 },
+    Write(String),
+    Disconnected,
+    /// Superseded by a newer operation on the same level, or cancelled outright.
+    Cancelled,
+    /// Refused because the level is already at its `[limits]` ceiling, or the step would pass it.
+    AtLimit { kind: LevelKind, limit: u8 },
+    /// Refused because the level has a limit but hasn't been calibrated, so the step can't be
+    /// checked against it.
+    Uncalibrated(LevelKind),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Timeout { attempts } => write!(f, "No confirmation after {} attempts", attempts),
+            CommandError::Write(e) => write!(f, "Write failed: {}", e),
+            CommandError::Disconnected => write!(f, "Not connected"),
+            CommandError::Cancelled => write!(f, "Cancelled"),
+            CommandError::AtLimit { kind, limit } => write!(f, "{} is limited to {} steps", kind, limit),
+            CommandError::Uncalibrated(kind) => write!(f, "{} is limited but uncalibrated; calibrate it first", kind),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+pub type CommandResult = Result<(), CommandError>;
+
+/// A queued command and, if the caller wants it, where to report the outcome.
+pub struct Request {
+    pub command: Command,
+    pub reply: Option<oneshot::Sender<CommandResult>>,
+}
+
+impl Request {
+    pub fn finish(self, result: CommandResult) {
+        if let Some(reply) = self.reply {
+            let _ = reply.send(result);
+        }
+    }
+}
+
+/// Writes `cmd` and waits for its confirmation on `acks`, rewriting it up to `max_retries` times
+/// unless it is a volume or bass step.
+/// Commands must go through here one at a time: confirmations carry no id, so only the order
+/// ties a c0xx/c1xx frame to the command that caused it.
+pub async fn send_with_ack<T: Z407Transport>(
+    transport: &mut T,
+    acks: &mut UnboundedReceiver<Response>,
+    cmd: Command,
+    policy: &AckPolicy,
+) -> CommandResult {
+    let expected = cmd.confirmation();
+    while acks.try_recv().is_ok() {}
+
+    let attempts = match LevelKind::stepped_by(cmd) {
+        Some(_) => 1,
+        None => policy.max_retries + 1,
+    };
+    for attempt in 1..=attempts {
+        transport.write(cmd).await.map_err(|e| CommandError::Write(e.to_string()))?;
+
+        let deadline = Instant::now() + policy.timeout;
+        loop {
+            match timeout_at(deadline, acks.recv()).await {
+                Ok(Some(resp)) if resp == expected => return Ok(()),
+                Ok(Some(_)) => continue,
+                Ok(None) => return Err(CommandError::Disconnected),
+                Err(_) => break,
+            }
+        }
+        if attempt < attempts {
+            eprintln!("No confirmation for {:?}, retrying ({}/{})", cmd, attempt, attempts - 1);
+        }
+    }
+
+    Err(CommandError::Timeout { attempts })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{DeviceInfo, MemoryHandle, MemoryTransport};
+    use tokio::sync::mpsc;
+
+    const POLICY: AckPolicy = AckPolicy { timeout: Duration::from_millis(50), max_retries: 2 };
+
+    /// A connected transport whose notifications are decoded onto the returned receiver.
+    async fn link() -> (MemoryTransport, MemoryHandle, UnboundedReceiver<Response>) {
+        let (mut transport, handle) = MemoryTransport::new();
+        let device = DeviceInfo { id: "memory".to_string(), name: None, rssi: None };
+        transport.connect(&device).await.unwrap();
+        let mut notifs = transport.subscribe().await.unwrap();
+        let (tx, acks) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(data) = notifs.recv().await {
+                let _ = tx.send(Response::decode(&data));
+            }
+        });
+        (transport, handle, acks)
+    }
+
+    #[tokio::test]
+    async fn waits_past_other_frames_for_the_confirmation() {
+        let (mut transport, handle, mut acks) = link().await;
+        handle.set_responder(|cmd| vec![Response::SwitchedUsb.encode(), vec![0xc0, 0x03], cmd.confirmation().encode()]);
+        assert_eq!(send_with_ack(&mut transport, &mut acks, Command::SwitchAux, &POLICY).await, Ok(()));
+        assert_eq!(handle.written(), [Command::SwitchAux]);
+    }
+
+    #[tokio::test]
+    async fn rewrites_an_unconfirmed_command() {
+        let (mut transport, handle, mut acks) = link().await;
+        let mut writes = 0;
+        handle.set_responder(move |cmd| {
+            writes += 1;
+            match writes {
+                1 => vec![],
+                _ => vec![cmd.confirmation().encode()],
+            }
+        });
+        assert_eq!(send_with_ack(&mut transport, &mut acks, Command::SwitchAux, &POLICY).await, Ok(()));
+        assert_eq!(handle.written(), [Command::SwitchAux, Command::SwitchAux]);
+    }
+
+    #[tokio::test]
+    async fn times_out_after_every_retry() {
+        let (mut transport, handle, mut acks) = link().await;
+        let result = send_with_ack(&mut transport, &mut acks, Command::PlayPause, &POLICY).await;
+        assert_eq!(result, Err(CommandError::Timeout { attempts: 3 }));
+        assert_eq!(handle.written(), [Command::PlayPause; 3]);
+    }
+
+    #[tokio::test]
+    async fn never_rewrites_a_level_step() {
+        let (mut transport, handle, mut acks) = link().await;
+        for cmd in [Command::VolumeUp, Command::VolumeDown, Command::BassUp, Command::BassDown] {
+            let result = send_with_ack(&mut transport, &mut acks, cmd, &POLICY).await;
+            assert_eq!(result, Err(CommandError::Timeout { attempts: 1 }));
+        }
+        let steps = [Command::VolumeUp, Command::VolumeDown, Command::BassUp, Command::BassDown];
+        assert_eq!(handle.written(), steps);
+    }
+
+    #[tokio::test]
+    async fn drops_confirmations_left_over_from_earlier_commands() {
+        let (mut transport, handle, mut acks) = link().await;
+        handle.notify(&Command::SwitchAux.confirmation().encode());
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let result = send_with_ack(&mut transport, &mut acks, Command::SwitchAux, &POLICY).await;
+        assert_eq!(result, Err(CommandError::Timeout { attempts: 3 }));
+    }
+}
@@ -0,0 +1,668 @@
+//! Optional TOML configuration shared by the GUI, CLI and daemon. Every key is optional and
+//! falls back to the built-in default; unknown keys are rejected so typos don't go unnoticed.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Deserializer};
+
+use crate::ack::AckPolicy;
+use crate::connection::ConnectionOptions;
+use crate::preset::{self, LevelTarget, Preset};
+use crate::reconnect::ReconnectPolicy;
+use crate::schedule::default_schedule_path;
+use crate::schedule::RuleAction;
+use crate::speakers;
+use crate::state::{BASS_STEPS, VOLUME_STEPS};
+use crate::triggers::Trigger;
+use crate::transport::DeviceFilter;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub device: DeviceConfig,
+    pub adapter: AdapterConfig,
+    pub ble: BleConfig,
+    pub timing: TimingConfig,
+    pub reconnect: ReconnectConfig,
+    pub ui: UiConfig,
+    pub scheduler: SchedulerConfig,
+    pub levels: LevelsConfig,
+    pub limits: LimitsConfig,
+    /// Named speakers, each with its own connection. Without any, `[device]` describes the one
+    /// speaker, called `default`.
+    #[serde(rename = "speaker")]
+    pub speakers: Vec<SpeakerConfig>,
+    /// Group name to speaker names, e.g. `downstairs = ["kitchen", "lounge"]`.
+    pub groups: BTreeMap<String, Vec<String>>,
+    /// `[presets.movie]` with any of `input`, `volume` and `bass`.
+    pub presets: BTreeMap<String, Preset>,
+    /// Reactions to speaker notifications and link changes.
+    #[serde(rename = "trigger")]
+    pub triggers: Vec<Trigger>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceConfig {
+    /// Only connect to the speaker with this address.
+    pub address: Option<String>,
+    /// Only connect to a speaker advertising this name.
+    pub name: Option<String>,
+    /// Record the last speaker connected to and stick to it while neither key above is set.
+    pub remember: bool,
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        Self { address: None, name: None, remember: true }
+    }
+}
+
+impl DeviceConfig {
+    pub fn filter(&self) -> DeviceFilter {
+        DeviceFilter { address: self.address.clone(), name: self.name.clone() }
+    }
+
+    pub fn is_pinned(&self) -> bool {
+        self.address.is_some() || self.name.is_some()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpeakerConfig {
+    /// What commands and the UI call this speaker.
+    pub name: String,
+    pub address: Option<String>,
+    /// The name the speaker advertises.
+    pub advertised_name: Option<String>,
+}
+
+impl SpeakerConfig {
+    pub fn filter(&self) -> DeviceFilter {
+        DeviceFilter { address: self.address.clone(), name: self.advertised_name.clone() }
+    }
+}
+
+/// bluest always uses the system's default adapter, so only how long to wait for it is
+/// configurable.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdapterConfig {
+    #[serde(deserialize_with = "duration")]
+    pub wait_timeout: Duration,
+}
+
+impl Default for AdapterConfig {
+    fn default() -> Self {
+        Self { wait_timeout: Duration::from_secs(30) }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BleConfig {
+    #[serde(deserialize_with = "uuid")]
+    pub service_uuid: u128,
+    #[serde(deserialize_with = "uuid")]
+    pub command_uuid: u128,
+    #[serde(deserialize_with = "uuid")]
+    pub response_uuid: u128,
+    /// Filled in from `[adapter]`.
+    #[serde(skip)]
+    pub adapter_wait_timeout: Duration,
+}
+
+impl Default for BleConfig {
+    fn default() -> Self {
+        Self {
+            service_uuid: 0x0000fdc2_0000_1000_8000_00805f9b34fb,
+            command_uuid: 0xc2e758b9_0e78_41e0_b0cb_98a593193fc5,
+            response_uuid: 0xb84ac9c6_29c5_46d4_bba1_9d534784330f,
+            adapter_wait_timeout: AdapterConfig::default().wait_timeout,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimingConfig {
+    #[serde(deserialize_with = "duration")]
+    pub scan_timeout: Duration,
+    #[serde(deserialize_with = "duration")]
+    pub handshake_step_timeout: Duration,
+    /// How often the command loop checks for queued commands.
+    #[serde(deserialize_with = "duration")]
+    pub poll_interval: Duration,
+    /// How often the supervisor checks for a scan request while disconnected.
+    #[serde(deserialize_with = "duration")]
+    pub idle_poll_interval: Duration,
+    #[serde(deserialize_with = "duration")]
+    pub ack_timeout: Duration,
+    pub ack_retries: u32,
+}
+
+impl Default for TimingConfig {
+    fn default() -> Self {
+        let options = ConnectionOptions::default();
+        Self {
+            scan_timeout: options.scan_timeout,
+            handshake_step_timeout: options.handshake_step_timeout,
+            poll_interval: options.poll_interval,
+            idle_poll_interval: options.idle_poll_interval,
+            ack_timeout: options.ack.timeout,
+            ack_retries: options.ack.max_retries,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReconnectConfig {
+    #[serde(deserialize_with = "duration")]
+    pub initial_delay: Duration,
+    #[serde(deserialize_with = "duration")]
+    pub max_delay: Duration,
+    pub multiplier: f64,
+    pub jitter: f64,
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        let policy = ReconnectPolicy::default();
+        Self {
+            initial_delay: policy.initial_delay,
+            max_delay: policy.max_delay,
+            multiplier: policy.multiplier,
+            jitter: policy.jitter,
+            max_attempts: policy.max_attempts,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Theme {
+    Dark,
+    Light,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UiConfig {
+    pub theme: Theme,
+    pub always_on_top: bool,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Default for UiConfig {
+    fn default() -> Self {
+        Self { theme: Theme::Dark, always_on_top: false, width: 350.0, height: 400.0 }
+    }
+}
+
+/// Step counts of each level's range. The speaker confirms a step even at either end, so the
+/// range can't be measured; these are assumed, and every percentage is a share of them.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LevelsConfig {
+    /// Steps from silence to full volume.
+    pub volume_steps: u8,
+    /// Steps from the lowest bass to the highest.
+    pub bass_steps: u8,
+}
+
+impl Default for LevelsConfig {
+    fn default() -> Self {
+        Self { volume_steps: VOLUME_STEPS, bass_steps: BASS_STEPS }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Volume is never raised past this, as a step count or a share of the range.
+    pub max_volume: Option<LevelTarget>,
+    pub max_bass: Option<LevelTarget>,
+}
+
+impl LimitsConfig {
+    pub fn max_volume(&self, volume_steps: u8) -> Option<u8> {
+        self.max_volume.map(|max| max.steps(volume_steps))
+    }
+
+    pub fn max_bass(&self, bass_steps: u8) -> Option<u8> {
+        self.max_bass.map(|max| max.steps(bass_steps))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchedulerConfig {
+    pub enabled: bool,
+    /// Where the rules live; `schedule.toml` next to the config file by default.
+    pub file: Option<PathBuf>,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self { enabled: true, file: None }
+    }
+}
+
+impl SchedulerConfig {
+    pub fn path(&self) -> Option<PathBuf> {
+        self.file.clone().or_else(default_schedule_path)
+    }
+}
+
+/// Accepts `"250ms"`, `"10s"`, `"1.5s"`, `"2m"` or `"1h"`.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let value: f64 = number.parse().map_err(|_| anyhow!("invalid duration {:?}, expected e.g. \"10s\" or \"250ms\"", s))?;
+    let secs = match unit.trim() {
+        "ms" => value / 1000.0,
+        "s" => value,
+        "m" => value * 60.0,
+        "h" => value * 3600.0,
+        _ => return Err(anyhow!("invalid duration unit in {:?}, expected ms, s, m or h", s)),
+    };
+    Duration::try_from_secs_f64(secs).map_err(|_| anyhow!("duration {:?} is too long", s))
+}
+
+pub(crate) fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    parse_duration(&s).map_err(serde::de::Error::custom)
+}
+
+fn uuid<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u128, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    let hex: String = s.chars().filter(|&c| c != '-').collect();
+    if hex.len() != 32 {
+        return Err(serde::de::Error::custom(format!("invalid UUID {:?}", s)));
+    }
+    u128::from_str_radix(&hex, 16).map_err(|_| serde::de::Error::custom(format!("invalid UUID {:?}", s)))
+}
+
+/// `$XDG_STATE_HOME/z407/last-device`, falling back to `~/.local/state`, or `%LOCALAPPDATA%`
+/// on Windows.
+pub fn last_device_path() -> Option<PathBuf> {
+    let base = if cfg!(windows) {
+        env::var_os("LOCALAPPDATA").map(PathBuf::from)
+    } else {
+        env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("state")))
+    };
+    base.map(|dir| dir.join("z407").join("last-device"))
+}
+
+pub fn last_device(path: &Path) -> Option<String> {
+    let id = fs::read_to_string(path).ok()?;
+    let id = id.trim();
+    (!id.is_empty()).then(|| id.to_string())
+}
+
+pub fn remember_device(path: &Path, id: &str) -> Result<()> {
+    if last_device(path).as_deref() == Some(id) {
+        return Ok(());
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, format!("{}\n", id))?;
+    Ok(())
+}
+
+pub fn forget_device(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// `$XDG_CONFIG_HOME/z407/config.toml`, falling back to `~/.config`, or `%APPDATA%` on Windows.
+pub fn default_config_path() -> Option<PathBuf> {
+    let base = if cfg!(windows) {
+        env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+    };
+    base.map(|dir| dir.join("z407").join("config.toml"))
+}
+
+impl Config {
+    /// Loads `path`, or the default path if none is given. A missing default file just means
+    /// the defaults; a missing explicit file is an error.
+    pub fn load(path: Option<&Path>) -> Result<Config> {
+        let (path, explicit) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_config_path() {
+                Some(path) => (path, false),
+                None => return Ok(Config::default()),
+            },
+        };
+        if !explicit && !path.exists() {
+            return Ok(Config::default());
+        }
+        let text = fs::read_to_string(&path).with_context(|| format!("Cannot read {}", path.display()))?;
+        Config::parse(&text).with_context(|| format!("Invalid config {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Config> {
+        let mut config: Config = toml::from_str(text)?;
+        config.validate()?;
+        config.ble.adapter_wait_timeout = config.adapter.wait_timeout;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let nonzero = [
+            ("adapter.wait_timeout", self.adapter.wait_timeout),
+            ("timing.scan_timeout", self.timing.scan_timeout),
+            ("timing.handshake_step_timeout", self.timing.handshake_step_timeout),
+            ("timing.poll_interval", self.timing.poll_interval),
+            ("timing.idle_poll_interval", self.timing.idle_poll_interval),
+            ("timing.ack_timeout", self.timing.ack_timeout),
+        ];
+        for (key, value) in nonzero {
+            if value.is_zero() {
+                return Err(anyhow!("`{}` must be greater than zero", key));
+            }
+        }
+        let r = &self.reconnect;
+        if r.initial_delay > r.max_delay {
+            return Err(anyhow!("`reconnect.initial_delay` must not exceed `reconnect.max_delay`"));
+        }
+        if r.multiplier < 1.0 {
+            return Err(anyhow!("`reconnect.multiplier` must be at least 1.0 (got {})", r.multiplier));
+        }
+        if !(0.0..=1.0).contains(&r.jitter) {
+            return Err(anyhow!("`reconnect.jitter` must be between 0 and 1 (got {})", r.jitter));
+        }
+        if self.ui.width <= 0.0 || self.ui.height <= 0.0 {
+            return Err(anyhow!("`ui.width` and `ui.height` must be positive"));
+        }
+        if self.levels.volume_steps == 0 || self.levels.bass_steps == 0 {
+            return Err(anyhow!("`levels.volume_steps` and `levels.bass_steps` must be at least 1"));
+        }
+        if let Some((name, _)) = self.presets.iter().find(|(_, p)| p.is_empty()) {
+            return Err(anyhow!("Preset {:?} sets none of `input`, `volume` and `bass`", name));
+        }
+        self.validate_speakers()?;
+        self.validate_triggers()?;
+        self.validate_limits()
+    }
+
+    /// Presets and triggers may not ask for more than the limits allow.
+    fn validate_limits(&self) -> Result<()> {
+        let targets = self
+            .presets
+            .iter()
+            .map(|(name, p)| (format!("Preset {:?}", name), p.volume, p.bass))
+            .chain(self.triggers.iter().map(|t| (format!("Trigger {:?}", t.when.to_string()), t.volume, t.bass)));
+        for (what, volume, bass) in targets {
+            let levels = [
+                ("volume", volume.map(|v| v.steps(self.levels.volume_steps)), self.limits.max_volume(self.levels.volume_steps)),
+                ("bass", bass.map(|b| b.steps(self.levels.bass_steps)), self.limits.max_bass(self.levels.bass_steps)),
+            ];
+            for (level, target, limit) in levels {
+                if let (Some(target), Some(limit)) = (target, limit) {
+                    if target > limit {
+                        return Err(anyhow!("{} sets {} to {} steps, above `limits.max_{}` ({} steps)", what, level, target, level, limit));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn preset(&self, name: &str) -> Result<&Preset> {
+        preset::find(&self.presets, name)
+    }
+
+    fn validate_speakers(&self) -> Result<()> {
+        if !self.speakers.is_empty() && self.device.is_pinned() {
+            return Err(anyhow!("`device.address` and `device.name` don't apply with [[speaker]] entries; set them per speaker"));
+        }
+        let mut names: Vec<&str> = Vec::new();
+        for speaker in &self.speakers {
+            let name = speaker.name.as_str();
+            if name.is_empty() || name == speakers::ALL {
+                return Err(anyhow!("`speaker.name` must not be empty or {:?}", speakers::ALL));
+            }
+            if names.contains(&name) {
+                return Err(anyhow!("Speaker {:?} is defined twice", name));
+            }
+            if self.speakers.len() > 1 && speaker.address.is_none() && speaker.advertised_name.is_none() {
+                return Err(anyhow!("Speaker {:?} needs an `address` or `advertised_name` when several are configured", name));
+            }
+            names.push(name);
+        }
+        let names: Vec<String> = self.speakers().into_iter().map(|s| s.name).collect();
+        for (group, members) in &self.groups {
+            if group == speakers::ALL || names.contains(group) {
+                return Err(anyhow!("Group {:?} clashes with a speaker name or {:?}", group, speakers::ALL));
+            }
+            if members.is_empty() {
+                return Err(anyhow!("Group {:?} is empty", group));
+            }
+            if let Some(unknown) = members.iter().find(|m| !names.contains(m)) {
+                return Err(anyhow!("Group {:?} names unknown speaker {:?}", group, unknown));
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_triggers(&self) -> Result<()> {
+        for trigger in &self.triggers {
+            if !trigger.has_action() {
+                return Err(anyhow!("Trigger {:?} has nothing to do; set `commands`, `preset`, `input`, `volume`, `bass` or `run`", trigger.when.to_string()));
+            }
+            self.resolve(trigger.speaker.as_deref())?;
+            let presets = trigger.commands.iter().filter_map(|a| match a {
+                RuleAction::Preset(name) => Some(name),
+                _ => None,
+            });
+            for name in presets.chain(&trigger.preset) {
+                self.preset(name)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The configured speakers, or the single `default` one described by `[device]`.
+    pub fn speakers(&self) -> Vec<SpeakerConfig> {
+        if !self.speakers.is_empty() {
+            return self.speakers.clone();
+        }
+        vec![SpeakerConfig {
+            name: speakers::DEFAULT.to_string(),
+            address: self.device.address.clone(),
+            advertised_name: self.device.name.clone(),
+        }]
+    }
+
+    /// The speakers a target names: one speaker, a group, or `all` (also the default).
+    pub fn resolve(&self, target: Option<&str>) -> Result<Vec<SpeakerConfig>> {
+        let all = self.speakers();
+        let names: Vec<&str> = all.iter().map(|s| s.name.as_str()).collect();
+        let selected = speakers::resolve(target, &names, &self.groups)?;
+        Ok(all.iter().filter(|s| selected.contains(&s.name.as_str())).cloned().collect())
+    }
+
+    /// `connection_options` for one of several configured speakers.
+    pub fn speaker_connection_options(&self, speaker: &SpeakerConfig) -> ConnectionOptions {
+        ConnectionOptions { device: speaker.filter(), ..self.connection_options() }
+    }
+
+    /// `connection_options` plus the remembered speaker, for a real BLE connection. Without a
+    /// pinned address or name, the last speaker connected to becomes the filter.
+    pub fn ble_connection_options(&self) -> ConnectionOptions {
+        self.remembering_connection_options(last_device_path())
+    }
+
+    /// `ble_connection_options` with the last speaker kept at `path` rather than the default.
+    pub(crate) fn remembering_connection_options(&self, path: Option<PathBuf>) -> ConnectionOptions {
+        let mut options = self.connection_options();
+        if self.device.remember {
+            options.remember_device = path;
+            if !self.device.is_pinned() {
+                if let Some(id) = options.remember_device.as_deref().and_then(last_device) {
+                    println!("Using remembered speaker {}", id);
+                    options.device.address = Some(id);
+                }
+            }
+        }
+        options
+    }
+
+    pub fn connection_options(&self) -> ConnectionOptions {
+        let t = &self.timing;
+        let r = &self.reconnect;
+        ConnectionOptions {
+            reconnect: ReconnectPolicy {
+                initial_delay: r.initial_delay,
+                max_delay: r.max_delay,
+                multiplier: r.multiplier,
+                jitter: r.jitter,
+                max_attempts: r.max_attempts,
+            },
+            ack: AckPolicy { timeout: t.ack_timeout, max_retries: t.ack_retries },
+            device: self.device.filter(),
+            remember_device: None,
+            scan_timeout: t.scan_timeout,
+            handshake_step_timeout: t.handshake_step_timeout,
+            poll_interval: t.poll_interval,
+            idle_poll_interval: t.idle_poll_interval,
+            volume_steps: self.levels.volume_steps,
+            max_volume: self.limits.max_volume(self.levels.volume_steps),
+            bass_steps: self.levels.bass_steps,
+            max_bass: self.limits.max_bass(self.levels.bass_steps),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> String {
+        format!("{:#}", Config::parse(text).unwrap_err())
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert_eq!(parse_duration(" 1.5s ").unwrap(), Duration::from_millis(1500));
+        assert_eq!(parse_duration("2m").unwrap(), Duration::from_secs(120));
+        assert_eq!(parse_duration("1.5h").unwrap(), Duration::from_secs(5400));
+        for bad in ["", "10", "10 days", "s", "1.2.3s", "-1s"] {
+            assert!(parse_duration(bad).is_err(), "{:?}", bad);
+        }
+        assert!(parse_duration("99999999999999999999h").unwrap_err().to_string().contains("too long"));
+    }
+
+    #[test]
+    fn names_the_key_of_a_bad_duration() {
+        let e = error("[timing]\nack_timeout = \"99999999999999999999h\"");
+        assert!(e.contains("ack_timeout") && e.contains("too long"), "{}", e);
+        let e = error("[reconnect]\ninitial_delay = \"soon\"");
+        assert!(e.contains("initial_delay") && e.contains("invalid duration"), "{}", e);
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert!(error("[timing]\nack_timeot = \"1s\"").contains("ack_timeot"));
+        assert!(error("colour = \"red\"").contains("colour"));
+        assert!(error("[presets.movie]\nvolum = 30").contains("volum"));
+    }
+
+    #[test]
+    fn checks_timings() {
+        assert!(error("[timing]\npoll_interval = \"0ms\"").contains("`timing.poll_interval` must be greater than zero"));
+        assert!(error("[adapter]\nwait_timeout = \"0s\"").contains("`adapter.wait_timeout`"));
+        assert!(error("[reconnect]\ninitial_delay = \"2m\"\nmax_delay = \"1m\"").contains("must not exceed"));
+        assert!(error("[reconnect]\nmultiplier = 0.5").contains("`reconnect.multiplier`"));
+        for jitter in ["-0.1", "1.5"] {
+            assert!(error(&format!("[reconnect]\njitter = {}", jitter)).contains("`reconnect.jitter`"), "{}", jitter);
+        }
+        assert!(Config::parse("[reconnect]\njitter = 1.0").is_ok());
+        assert!(error("[levels]\nbass_steps = 0").contains("at least 1"));
+    }
+
+    #[test]
+    fn checks_speakers_and_groups() {
+        let two = "[[speaker]]\nname = \"kitchen\"\naddress = \"AA\"\n[[speaker]]\nname = \"lounge\"\naddress = \"BB\"\n";
+        let config = Config::parse(&format!("{}[groups]\ndownstairs = [\"kitchen\", \"lounge\"]", two)).unwrap();
+        assert_eq!(config.resolve(Some("downstairs")).unwrap().len(), 2);
+
+        assert!(error(&format!("{}[device]\nname = \"Z407\"", two)).contains("per speaker"));
+        assert!(error("[[speaker]]\nname = \"all\"").contains("must not be empty"));
+        assert!(error("[[speaker]]\nname = \"a\"\naddress = \"AA\"\n[[speaker]]\nname = \"a\"\naddress = \"BB\"").contains("defined twice"));
+        assert!(error("[[speaker]]\nname = \"a\"\n[[speaker]]\nname = \"b\"\naddress = \"BB\"").contains("needs an `address`"));
+        assert!(error(&format!("{}[groups]\nkitchen = [\"lounge\"]", two)).contains("clashes"));
+        assert!(error(&format!("{}[groups]\nnone = []", two)).contains("is empty"));
+        assert!(error(&format!("{}[groups]\nup = [\"attic\"]", two)).contains("unknown speaker \"attic\""));
+    }
+
+    #[test]
+    fn checks_presets_and_triggers() {
+        assert!(error("[presets.nothing]").contains("sets none of"));
+        assert!(error("[[trigger]]\nwhen = \"connected\"").contains("nothing to do"));
+        assert!(error("[[trigger]]\nwhen = \"connected\"\npreset = \"movie\"").contains("movie"));
+        assert!(error("[[trigger]]\nwhen = \"connected\"\ninput = \"aux\"\nspeaker = \"attic\"").contains("attic"));
+        assert!(error("[[trigger]]\nwhen = \"warp\"\ninput = \"aux\"").contains("Unknown trigger"));
+        let config = Config::parse("[presets.movie]\ninput = \"aux\"\n[[trigger]]\nwhen = \"switched_aux\"\npreset = \"movie\"").unwrap();
+        assert_eq!(config.triggers.len(), 1);
+    }
+
+    #[test]
+    fn checks_targets_against_limits() {
+        let limited = "[limits]\nmax_volume = \"50%\"\n";
+        let config = Config::parse(&format!("{}[presets.quiet]\nvolume = 25", limited)).unwrap();
+        assert_eq!(config.connection_options().max_volume, Some(25));
+        let e = error(&format!("{}[presets.loud]\nvolume = 26", limited));
+        assert!(e.contains("Preset \"loud\" sets volume to 26 steps, above `limits.max_volume` (25 steps)"), "{}", e);
+        let e = error(&format!("{}[[trigger]]\nwhen = \"connected\"\nvolume = \"60%\"", limited));
+        assert!(e.contains("Trigger \"connected\" sets volume to 30 steps"), "{}", e);
+        // The range from `[levels]` is what percentages are a share of.
+        let config = Config::parse(&format!("{}[levels]\nvolume_steps = 100\n[presets.loud]\nvolume = 50", limited)).unwrap();
+        assert_eq!(config.connection_options().max_volume, Some(50));
+    }
+
+    #[test]
+    fn sticks_to_the_remembered_speaker_unless_pinned() {
+        let dir = env::temp_dir().join(format!("z407-config-test-{}", std::process::id()));
+        let path = dir.join("last-device");
+        let options = |text: &str| Config::parse(text).unwrap().remembering_connection_options(Some(path.clone()));
+
+        let fresh = options("");
+        assert_eq!(fresh.remember_device.as_ref(), Some(&path));
+        assert_eq!(fresh.device, DeviceFilter::default());
+
+        remember_device(&path, "AA:BB:CC:DD:EE:FF").unwrap();
+        let remembered = options("");
+        assert_eq!(remembered.device.address.as_deref(), Some("AA:BB:CC:DD:EE:FF"));
+        assert_eq!(remembered.device.name, None);
+
+        let pinned = options("[device]\nname = \"Z407\"");
+        assert_eq!(pinned.device, DeviceFilter { address: None, name: Some("Z407".to_string()) });
+        assert_eq!(pinned.remember_device.as_ref(), Some(&path));
+
+        let forgetful = options("[device]\nremember = false");
+        assert_eq!((forgetful.device, forgetful.remember_device), (DeviceFilter::default(), None));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
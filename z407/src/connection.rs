@@ -0,0 +1,536 @@
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use tokio::runtime::Handle;
+use tokio::sync::{broadcast, mpsc as tokio_mpsc, oneshot};
+use tokio::time::sleep;
+
+use crate::ack::{self, AckPolicy, CommandError, CommandResult, Request};
+use crate::config;
+#[cfg(feature = "bluest")]
+use crate::config::Config;
+use crate::controller::Z407Controller;
+use crate::event::Event;
+use crate::handshake;
+use crate::level::LevelKind;
+use crate::protocol::{Command, Response};
+use crate::reconnect::{ReconnectPolicy, RetryState};
+use crate::state::{Z407State, BASS_STEPS, VOLUME_STEPS};
+#[cfg(feature = "bluest")]
+use crate::transport::BleTransport;
+use crate::transport::{DeviceFilter, Z407Transport};
+
+#[derive(Debug, Clone)]
+pub struct ConnectionOptions {
+    pub reconnect: ReconnectPolicy,
+    pub ack: AckPolicy,
+    /// A scanned speaker that doesn't match is refused.
+    pub device: DeviceFilter,
+    /// Where to record the id of each speaker the handshake succeeds with.
+    pub remember_device: Option<PathBuf>,
+    pub scan_timeout: Duration,
+    /// Allowed per handshake step.
+    pub handshake_step_timeout: Duration,
+    pub poll_interval: Duration,
+    pub idle_poll_interval: Duration,
+    /// Assumed lengths of the volume and bass ranges; see `Level::max_steps`.
+    pub volume_steps: u8,
+    pub bass_steps: u8,
+    /// Step limits for volume and bass; see `Level::limit`.
+    pub max_volume: Option<u8>,
+    pub max_bass: Option<u8>,
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> Self {
+        Self {
+            reconnect: ReconnectPolicy::default(),
+            ack: AckPolicy::default(),
+            device: DeviceFilter::default(),
+            remember_device: None,
+            scan_timeout: Duration::from_secs(10),
+            handshake_step_timeout: Duration::from_secs(2),
+            poll_interval: Duration::from_millis(50),
+            idle_poll_interval: Duration::from_millis(200),
+            volume_steps: VOLUME_STEPS,
+            bass_steps: BASS_STEPS,
+            max_volume: None,
+            max_bass: None,
+        }
+    }
+}
+
+pub struct Z407Connection {
+    pub state: Arc<Mutex<Z407State>>,
+    controller: Z407Controller,
+    runtime: Handle,
+    resp_rx: mpsc::Receiver<Response>,
+}
+
+impl Z407Connection {
+    #[cfg(feature = "bluest")]
+    pub fn spawn(state: Z407State) -> Self {
+        Self::spawn_with(state, BleTransport::new(), ConnectionOptions::default())
+    }
+
+    /// Connects over BLE with `config`, including the remembered last-used speaker.
+    #[cfg(feature = "bluest")]
+    pub fn spawn_configured(state: Z407State, config: &Config) -> Self {
+        let options = config.ble_connection_options();
+        Self::spawn_with(state, BleTransport::with_config(config.ble.clone()), options)
+    }
+
+    pub fn spawn_with<T: Z407Transport>(mut state: Z407State, transport: T, options: ConnectionOptions) -> Self {
+        state.volume.max_steps = options.volume_steps;
+        state.volume.limit = options.max_volume;
+        state.bass.max_steps = options.bass_steps;
+        state.bass.limit = options.max_bass;
+        let state = Arc::new(Mutex::new(state));
+        let state_clone = state.clone();
+        let (cmd_tx, cmd_rx) = mpsc::channel::<Request>();
+        let (resp_tx, resp_rx) = mpsc::channel::<Response>();
+        let (events, _) = broadcast::channel::<Event>(EVENT_CAPACITY);
+        let events_clone = events.clone();
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let runtime = rt.handle().clone();
+        thread::spawn(move || {
+            println!("BLE thread started");
+            rt.block_on(ble_loop(transport, options, state_clone, cmd_rx, resp_tx, events_clone));
+        });
+
+        let controller = Z407Controller::new(state.clone(), cmd_tx, events);
+        Self { state, controller, runtime, resp_rx }
+    }
+
+    pub fn controller(&self) -> Z407Controller {
+        self.controller.clone()
+    }
+
+    /// The runtime driving the BLE thread, for running controller operations from sync code.
+    pub fn runtime(&self) -> &Handle {
+        &self.runtime
+    }
+
+    pub fn send(&self, cmd: Command) {
+        self.controller.send(cmd);
+    }
+
+    pub fn submit(&self, cmd: Command) -> oneshot::Receiver<CommandResult> {
+        self.controller.submit(cmd)
+    }
+
+    pub async fn execute(&self, cmd: Command) -> CommandResult {
+        self.controller.execute(cmd).await
+    }
+
+    /// Starts a connection attempt right away, skipping any pending backoff.
+    pub fn request_scan(&self) {
+        self.controller.request_scan();
+    }
+
+    pub fn snapshot(&self) -> Z407State {
+        self.state.lock().unwrap().clone()
+    }
+
+    pub fn responses(&self) -> Vec<Response> {
+        self.resp_rx.try_iter().collect()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.controller.subscribe()
+    }
+}
+
+const EVENT_CAPACITY: usize = 256;
+/// Unconfirmed steps down, backing off a little more after each, before a level over its
+/// limit is marked uncalibrated instead.
+const MAX_ENFORCE_FAILURES: u32 = 3;
+
+fn dispatch(
+    state: &Mutex<Z407State>,
+    resp_tx: &mpsc::Sender<Response>,
+    events: &broadcast::Sender<Event>,
+    resp: Response,
+) {
+    state.lock().unwrap().apply(&resp);
+    let _ = events.send(resp.clone().into());
+    let _ = resp_tx.send(resp);
+}
+
+/// Supervises the link: runs a session whenever a connection is wanted and schedules the next
+/// attempt with exponential backoff after a failure or a dropped link. Never returns.
+pub async fn ble_loop<T: Z407Transport>(
+    mut transport: T,
+    options: ConnectionOptions,
+    state: Arc<Mutex<Z407State>>,
+    cmd_rx: mpsc::Receiver<Request>,
+    resp_tx: mpsc::Sender<Response>,
+    events: broadcast::Sender<Event>,
+) {
+    let policy = &options.reconnect;
+    loop {
+        let due = {
+            let s = state.lock().unwrap();
+            s.scan_requested && s.retry.as_ref().is_none_or(|r| Instant::now() >= r.retry_at)
+        };
+        if !due {
+            for request in cmd_rx.try_iter() {
+                request.finish(Err(CommandError::Disconnected));
+            }
+            sleep(options.idle_poll_interval).await;
+            continue;
+        }
+
+        let result = session(&mut transport, &options, &state, &cmd_rx, &resp_tx, &events).await;
+
+        let mut s = state.lock().unwrap();
+        s.connected = false;
+        // The puck may move either level while the link is down.
+        s.volume.steps = None;
+        s.bass.steps = None;
+        let attempt = match result {
+            Ok(()) => {
+                let _ = events.send(Event::Disconnected);
+                1
+            }
+            Err(e) => {
+                eprintln!("Connection attempt failed: {}", e);
+                let _ = events.send(Event::ConnectFailed { error: e.to_string() });
+                s.last_error = Some(e.to_string());
+                s.retry.as_ref().map_or(0, |r| r.attempt) + 1
+            }
+        };
+        if policy.exhausted(attempt) {
+            eprintln!("Giving up after {} retries.", attempt - 1);
+            let _ = events.send(Event::GaveUp { retries: attempt - 1 });
+            s.scan_requested = false;
+            s.retry = None;
+            continue;
+        }
+        let delay = policy.delay(attempt);
+        println!("Reconnecting in {:.1}s (attempt {})", delay.as_secs_f64(), attempt);
+        let _ = events.send(Event::Reconnecting { attempt, delay_ms: delay.as_millis() as u64 });
+        s.scan_requested = true;
+        s.retry = Some(RetryState { attempt, retry_at: Instant::now() + delay });
+    }
+}
+
+/// One connection: scan, connect, handshake, then pump commands until the link drops.
+/// Returns `Ok` once a handshaken link has dropped and `Err` if it never got that far.
+async fn session<T: Z407Transport>(
+    transport: &mut T,
+    options: &ConnectionOptions,
+    state: &Arc<Mutex<Z407State>>,
+    cmd_rx: &mpsc::Receiver<Request>,
+    resp_tx: &mpsc::Sender<Response>,
+    events: &broadcast::Sender<Event>,
+) -> Result<()> {
+    println!("Scan requested.");
+    let _ = events.send(Event::Scanning);
+    let device = transport.scan(options.scan_timeout, &options.device).await?
+        .ok_or_else(|| match options.device == DeviceFilter::default() {
+            true => anyhow!("Z407 not found in scan"),
+            false => anyhow!("{} not found in scan", options.device),
+        })?;
+    if !options.device.matches(&device) {
+        return Err(anyhow!("Found {}, which is not the configured speaker", device.id));
+    }
+
+    let device_name = device.name.clone().unwrap_or_else(|| "Unknown".to_string());
+    println!("Found device: {}, connecting...", device_name);
+    let _ = events.send(Event::Connecting { device: device_name.clone() });
+
+    transport.connect(&device).await?;
+
+    let mut notifs = transport.subscribe().await?;
+    let result = handshake::perform(
+        transport,
+        &mut notifs,
+        options.handshake_step_timeout,
+        |resp| dispatch(state, resp_tx, events, resp),
+        |step| {
+            let _ = events.send(Event::Handshake { step });
+        },
+    )
+    .await;
+    if let Err(e) = result {
+        let _ = transport.disconnect().await;
+        return Err(e.into());
+    }
+    println!("Handshake complete");
+    if let Some(path) = &options.remember_device {
+        if let Err(e) = config::remember_device(path, &device.id) {
+            eprintln!("Could not remember {}: {}", device.id, e);
+        }
+    }
+
+    let (ack_tx, mut acks) = tokio_mpsc::unbounded_channel();
+    let state_clone = state.clone();
+    let resp_tx_clone = resp_tx.clone();
+    let events_clone = events.clone();
+    tokio::spawn(async move {
+        while let Some(data) = notifs.recv().await {
+            let resp = Response::decode(&data);
+            dispatch(&state_clone, &resp_tx_clone, &events_clone, resp.clone());
+            let _ = ack_tx.send(resp);
+        }
+    });
+
+    {
+        let mut s = state.lock().unwrap();
+        s.connected = true;
+        s.device = Some(device.id.clone());
+        s.last_error = None;
+        s.retry = None;
+        s.scan_requested = false;
+    }
+    let _ = events.send(Event::Connected { device: device_name });
+
+    // Failed attempts to pull a level back under its limit, and when to try again.
+    let mut enforce_failures = 0;
+    let mut enforce_at = Instant::now();
+    loop {
+        if !transport.is_connected() {
+            println!("Device disconnected.");
+            break;
+        }
+        // Pulling a level back under its limit goes ahead of the next queued request, which
+        // can't raise it meanwhile: `check_limits` refuses that.
+        let over = {
+            let s = state.lock().unwrap();
+            [LevelKind::Volume, LevelKind::Bass]
+                .into_iter()
+                .map(|kind| (kind, s.level(kind)))
+                .find(|(_, level)| level.excess() > 0)
+        };
+        if let Some((kind, level)) = over.filter(|_| Instant::now() >= enforce_at) {
+            let limit = level.limit.unwrap_or_default();
+            println!("{} at {} steps is above its limit of {}, stepping down", kind, level.steps.unwrap_or_default(), limit);
+            let _ = events.send(Event::LimitEnforced { level: kind, limit });
+            let result = ack::send_with_ack(transport, &mut acks, kind.down(), &options.ack).await;
+            match &result {
+                Ok(()) => enforce_failures = 0,
+                Err(e) => {
+                    eprintln!("Stepping {} down failed: {}", kind, e);
+                    enforce_failures += 1;
+                    enforce_at = Instant::now() + options.ack.timeout * enforce_failures;
+                    let mut s = state.lock().unwrap();
+                    s.last_error = Some(format!("{:?}: {}", kind.down(), e));
+                    // Without confirmations the count can't be trusted to say where the level is.
+                    if enforce_failures >= MAX_ENFORCE_FAILURES {
+                        eprintln!("Giving up on {} after {} failed steps; it needs calibrating", kind, enforce_failures);
+                        s.level_mut(kind).steps = None;
+                        enforce_failures = 0;
+                    }
+                }
+            }
+            if matches!(result, Err(CommandError::Write(_))) {
+                let _ = transport.disconnect().await;
+                break;
+            }
+        }
+        if let Ok(request) = cmd_rx.try_recv() {
+            let cmd = request.command;
+            let checked = state.lock().unwrap().check_limits(cmd);
+            let result = match checked {
+                Ok(()) => ack::send_with_ack(transport, &mut acks, cmd, &options.ack).await,
+                Err(e) => Err(e),
+            };
+            let failed_write = matches!(result, Err(CommandError::Write(_)));
+            if let Err(e) = &result {
+                eprintln!("{:?} failed: {}", cmd, e);
+                state.lock().unwrap().last_error = Some(format!("{:?}: {}", cmd, e));
+            }
+            request.finish(result);
+            if failed_write {
+                let _ = transport.disconnect().await;
+                break;
+            }
+        }
+        sleep(options.poll_interval).await;
+    }
+
+    println!("Command loop exited.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::{Simulator, SimulatorConfig};
+    use crate::transport::{MemoryHandle, MemoryTransport};
+
+    /// Answers the handshake and confirms every other command, except those `ignored`.
+    fn speaker(ignored: Option<Command>) -> impl FnMut(Command) -> Vec<Vec<u8>> + Send + 'static {
+        move |cmd| match cmd {
+            Command::Acknowledge => vec![Response::Acknowledged.encode(), Response::Connected.encode()],
+            cmd if Some(cmd) == ignored => vec![],
+            cmd => vec![cmd.confirmation().encode()],
+        }
+    }
+
+    fn spawn() -> (Z407Connection, MemoryHandle) {
+        spawn_from(Z407State::default(), None, None)
+    }
+
+    fn spawn_from(state: Z407State, max_volume: Option<u8>, ignored: Option<Command>) -> (Z407Connection, MemoryHandle) {
+        let (transport, handle) = MemoryTransport::new();
+        handle.set_responder(speaker(ignored));
+        let state = Z407State { scan_requested: true, ..state };
+        (Z407Connection::spawn_with(state, transport, options(max_volume)), handle)
+    }
+
+    fn options(max_volume: Option<u8>) -> ConnectionOptions {
+        ConnectionOptions {
+            reconnect: ReconnectPolicy { initial_delay: Duration::from_millis(50), jitter: 0.0, ..Default::default() },
+            ack: AckPolicy { timeout: Duration::from_millis(100), max_retries: 2 },
+            handshake_step_timeout: Duration::from_millis(100),
+            poll_interval: Duration::from_millis(5),
+            idle_poll_interval: Duration::from_millis(5),
+            max_volume,
+            ..Default::default()
+        }
+    }
+
+    fn wait_until(what: &str, mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out waiting until {}", what);
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn connects_after_the_handshake() {
+        let (conn, handle) = spawn();
+        wait_until("connected", || conn.snapshot().connected);
+        assert_eq!(conn.snapshot().device.as_deref(), Some("memory"));
+        assert_eq!(handle.written(), [Command::Initiate, Command::Acknowledge]);
+    }
+
+    #[test]
+    fn matches_a_command_to_its_confirmation() {
+        let (conn, handle) = spawn();
+        wait_until("connected", || conn.snapshot().connected);
+        // A puck notification ahead of the confirmation doesn't count as it.
+        handle.set_responder(|cmd| vec![Response::SwitchedUsb.encode(), cmd.confirmation().encode()]);
+        assert_eq!(conn.runtime().block_on(conn.execute(Command::SwitchAux)), Ok(()));
+        assert_eq!(handle.written().last(), Some(&Command::SwitchAux));
+
+        handle.set_responder(|_| vec![Response::SwitchedUsb.encode()]);
+        let result = conn.runtime().block_on(conn.execute(Command::SwitchAux));
+        assert_eq!(result, Err(CommandError::Timeout { attempts: 3 }));
+        let writes = handle.written().iter().filter(|c| **c == Command::SwitchAux).count();
+        assert_eq!(writes, 4);
+    }
+
+    #[test]
+    fn reconnects_with_backoff_after_a_drop() {
+        let (conn, handle) = spawn();
+        wait_until("connected", || conn.snapshot().connected);
+        let mut events = conn.subscribe();
+
+        handle.disconnect();
+        wait_until("disconnected", || !conn.snapshot().connected);
+        wait_until("reconnected", || conn.snapshot().connected);
+
+        let events: Vec<Event> = std::iter::from_fn(|| events.try_recv().ok()).collect();
+        let dropped = events.iter().position(|e| matches!(e, Event::Disconnected)).expect("no disconnected event");
+        assert!(matches!(events[dropped + 1], Event::Reconnecting { attempt: 1, delay_ms: 50 }));
+        assert!(events[dropped..].iter().any(|e| matches!(e, Event::Connected { .. })));
+        let handshakes = handle.written().iter().filter(|c| **c == Command::Initiate).count();
+        assert_eq!(handshakes, 2);
+    }
+
+    #[test]
+    fn fails_queued_commands_while_disconnected() {
+        let (conn, handle) = spawn();
+        wait_until("connected", || conn.snapshot().connected);
+        handle.set_advertising(false);
+        handle.disconnect();
+        wait_until("disconnected", || !conn.snapshot().connected);
+        assert_eq!(conn.runtime().block_on(conn.execute(Command::PlayPause)), Err(CommandError::Disconnected));
+    }
+
+    #[test]
+    fn gives_up_enforcing_a_limit_the_speaker_ignores() {
+        let mut state = Z407State::default();
+        state.volume.steps = Some(30);
+        let (conn, handle) = spawn_from(state, Some(20), Some(Command::VolumeDown));
+        wait_until("connected", || conn.snapshot().connected);
+
+        // Other commands still get through while the steps down go unconfirmed.
+        assert_eq!(conn.runtime().block_on(conn.execute(Command::PlayPause)), Ok(()));
+        wait_until("volume uncalibrated", || !conn.snapshot().volume.is_calibrated());
+        let snapshot = conn.snapshot();
+        assert_eq!(snapshot.volume.excess(), 0);
+        assert!(snapshot.last_error.is_some_and(|e| e.starts_with("VolumeDown")));
+
+        // Level steps are never rewritten, so three writes and then no more.
+        let downs = || handle.written().iter().filter(|c| **c == Command::VolumeDown).count();
+        assert_eq!(downs(), 3);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(downs(), 3);
+    }
+
+    #[test]
+    fn refuses_raising_a_limited_level_until_calibrated() {
+        let (conn, handle) = spawn_from(Z407State::default(), Some(20), None);
+        wait_until("connected", || conn.snapshot().connected);
+
+        let result = conn.runtime().block_on(conn.execute(Command::VolumeUp));
+        assert_eq!(result, Err(CommandError::Uncalibrated(LevelKind::Volume)));
+        assert!(!handle.written().contains(&Command::VolumeUp));
+        assert_eq!(conn.runtime().block_on(conn.controller().calibrate_volume()), Ok(()));
+        assert_eq!(conn.runtime().block_on(conn.execute(Command::VolumeUp)), Ok(()));
+        assert_eq!(conn.snapshot().volume.steps, Some(1));
+    }
+
+    #[test]
+    fn forgets_the_levels_when_the_link_drops() {
+        let (simulator, puck) = Simulator::new(SimulatorConfig::default());
+        let state = Z407State { scan_requested: true, ..Default::default() };
+        let conn = Z407Connection::spawn_with(state, simulator, options(None));
+        wait_until("connected", || conn.snapshot().connected);
+        let ctl = conn.controller();
+        assert_eq!(conn.runtime().block_on(ctl.set_volume(10)), Ok(()));
+        assert_eq!(conn.runtime().block_on(ctl.set_bass(5)), Ok(()));
+
+        // Twists while the link is down go unseen.
+        puck.drop_link();
+        puck.twist(true);
+        wait_until("disconnected", || !conn.snapshot().connected);
+        wait_until("reconnected", || conn.snapshot().connected);
+        assert_eq!(puck.volume(), 11);
+        let snapshot = conn.snapshot();
+        assert_eq!((snapshot.volume.steps, snapshot.bass.steps), (None, None));
+    }
+
+    #[test]
+    fn connects_only_to_the_remembered_speaker() {
+        let dir = std::env::temp_dir().join(format!("z407-connection-test-{}", std::process::id()));
+        let path = dir.join("last-device");
+        config::remember_device(&path, "AA:BB:CC:DD:EE:FF").unwrap();
+        let remembered = config::Config::default().remembering_connection_options(Some(path.clone()));
+        let options = ConnectionOptions { device: remembered.device, remember_device: remembered.remember_device, ..options(None) };
+        let state = || Z407State { scan_requested: true, ..Default::default() };
+
+        let (other, other_puck) = Simulator::new(SimulatorConfig { id: "11:22:33:44:55:66".to_string(), ..Default::default() });
+        let conn = Z407Connection::spawn_with(state(), other, options.clone());
+        wait_until("a scan", || conn.snapshot().last_error.is_some());
+        thread::sleep(Duration::from_millis(50));
+        assert!(!conn.snapshot().connected && !other_puck.is_connected());
+        assert_eq!(conn.snapshot().last_error.as_deref(), Some("AA:BB:CC:DD:EE:FF not found in scan"));
+
+        let (remembered, puck) = Simulator::new(SimulatorConfig { id: "aa:bb:cc:dd:ee:ff".to_string(), ..Default::default() });
+        let conn = Z407Connection::spawn_with(state(), remembered, options);
+        wait_until("connected", || conn.snapshot().connected);
+        assert!(puck.is_connected());
+        assert_eq!(config::last_device(&path).as_deref(), Some("aa:bb:cc:dd:ee:ff"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,455 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::{broadcast, oneshot};
+use tokio::time::sleep;
+
+use crate::ack::{CommandError, CommandResult, Request};
+use crate::event::Event;
+use crate::fade::Easing;
+use crate::level::LevelKind;
+use crate::preset::{Preset, PresetProgress};
+use crate::protocol::Command;
+use crate::state::Z407State;
+
+/// How often a fade checks whether its next step is due.
+const FADE_TICK: Duration = Duration::from_millis(20);
+
+/// Cloneable handle for issuing commands to a running connection from any thread or task.
+#[derive(Clone)]
+pub struct Z407Controller {
+    pub state: Arc<Mutex<Z407State>>,
+    cmd_tx: mpsc::Sender<Request>,
+    events: broadcast::Sender<Event>,
+    volume_op: Arc<AtomicU64>,
+    bass_op: Arc<AtomicU64>,
+    preset_op: Arc<AtomicU64>,
+}
+
+impl Z407Controller {
+    pub(crate) fn new(
+        state: Arc<Mutex<Z407State>>,
+        cmd_tx: mpsc::Sender<Request>,
+        events: broadcast::Sender<Event>,
+    ) -> Self {
+        Self {
+            state,
+            cmd_tx,
+            events,
+            volume_op: Arc::new(AtomicU64::new(0)),
+            bass_op: Arc::new(AtomicU64::new(0)),
+            preset_op: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Like `submit`, without waiting for the outcome.
+    pub fn send(&self, cmd: Command) {
+        self.interrupt(cmd);
+        let _ = self.cmd_tx.send(Request { command: cmd, reply: None });
+    }
+
+    /// Queues `cmd` and returns a receiver for its outcome once the speaker confirms it or the
+    /// retries run out. Use `.await` from async code or `blocking_recv()` from a plain thread.
+    /// A volume or bass step cancels any `set_level`, `calibrate` or fade running on that level.
+    pub fn submit(&self, cmd: Command) -> oneshot::Receiver<CommandResult> {
+        self.interrupt(cmd);
+        self.queue(cmd)
+    }
+
+    pub async fn execute(&self, cmd: Command) -> CommandResult {
+        self.submit(cmd).await.unwrap_or(Err(CommandError::Disconnected))
+    }
+
+    fn interrupt(&self, cmd: Command) {
+        if let Some(kind) = LevelKind::stepped_by(cmd) {
+            self.op_counter(kind).fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn queue(&self, cmd: Command) -> oneshot::Receiver<CommandResult> {
+        let (tx, rx) = oneshot::channel();
+        let request = Request { command: cmd, reply: Some(tx) };
+        if let Err(mpsc::SendError(request)) = self.cmd_tx.send(request) {
+            request.finish(Err(CommandError::Disconnected));
+        }
+        rx
+    }
+
+    /// `execute` for the steps of a level operation, which mustn't cancel the operation itself.
+    async fn step(&self, cmd: Command) -> CommandResult {
+        self.queue(cmd).await.unwrap_or(Err(CommandError::Disconnected))
+    }
+
+    /// Receives every `Event` from now on. A subscriber that falls too far behind gets
+    /// `RecvError::Lagged` and skips ahead.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Starts a connection attempt right away, skipping any pending backoff.
+    pub fn request_scan(&self) {
+        let mut s = self.state.lock().unwrap();
+        s.scan_requested = true;
+        s.retry = None;
+    }
+
+    pub async fn calibrate_volume(&self) -> CommandResult {
+        self.calibrate(LevelKind::Volume).await
+    }
+
+    pub async fn set_volume(&self, target: u8) -> CommandResult {
+        self.set_level(LevelKind::Volume, target).await
+    }
+
+    pub async fn calibrate_bass(&self) -> CommandResult {
+        self.calibrate(LevelKind::Bass).await
+    }
+
+    pub async fn set_bass(&self, target: u8) -> CommandResult {
+        self.set_level(LevelKind::Bass, target).await
+    }
+
+    /// Drives the level to its floor with one down step per possible step, after which
+    /// confirmed responses are counted from zero up to the level's `max_steps` ceiling.
+    pub async fn calibrate(&self, kind: LevelKind) -> CommandResult {
+        let op = self.op_counter(kind).fetch_add(1, Ordering::SeqCst) + 1;
+        self.drive_to_floor(kind, op, &mut || {}).await
+    }
+
+    /// Steps the level to `target` (clamped to the step range), calibrating first if needed.
+    /// A target above the level's limit is refused. A later `set_level` or `calibrate` for the
+    /// same level cancels this one.
+    pub async fn set_level(&self, kind: LevelKind, target: u8) -> CommandResult {
+        self.step_to(kind, target, &mut || {}).await
+    }
+
+    /// Switches to the preset's input, then steps volume and bass to its targets, keeping
+    /// `Z407State::preset` up to date. A later preset or `cancel` stops it between steps.
+    pub async fn apply_preset(&self, name: &str, preset: &Preset) -> CommandResult {
+        let op = self.preset_op.fetch_add(1, Ordering::SeqCst) + 1;
+        let total = preset.estimate_steps(&self.state.lock().unwrap());
+        let mut done = 0;
+        self.preset_progress(op, name, done, total);
+        let mut on_step = || {
+            done += 1;
+            self.preset_progress(op, name, done.min(total), total);
+        };
+
+        let mut result = Ok(());
+        if let Some(input) = preset.input {
+            if self.state.lock().unwrap().current_input != Some(input) {
+                result = self.execute(input.command()).await;
+                on_step();
+            }
+        }
+        for (kind, target) in [(LevelKind::Volume, preset.volume), (LevelKind::Bass, preset.bass)] {
+            let Some(target) = target else { continue };
+            if result.is_err() {
+                break;
+            }
+            if self.preset_op.load(Ordering::SeqCst) != op {
+                result = Err(CommandError::Cancelled);
+                break;
+            }
+            let max_steps = self.state.lock().unwrap().level(kind).max_steps;
+            result = self.step_to(kind, target.steps(max_steps), &mut on_step).await;
+        }
+
+        // Otherwise a newer preset owns the progress, or `cancel` already cleared it.
+        let mut s = self.state.lock().unwrap();
+        if self.preset_op.load(Ordering::SeqCst) == op {
+            s.preset = None;
+        }
+        drop(s);
+        let error = result.as_ref().err().map(|e| e.to_string());
+        let _ = self.events.send(Event::PresetFinished { name: name.to_string(), error });
+        result
+    }
+
+    /// Stops any running preset, `set_level` or `calibrate` before its next step.
+    pub fn cancel(&self) {
+        let mut s = self.state.lock().unwrap();
+        for counter in [&self.preset_op, &self.volume_op, &self.bass_op] {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }
+        s.preset = None;
+    }
+
+    /// Only preset `op` may report, so a superseded or cancelled one can't bring back its bar.
+    fn preset_progress(&self, op: u64, name: &str, done: u32, total: u32) {
+        let mut s = self.state.lock().unwrap();
+        if self.preset_op.load(Ordering::SeqCst) != op {
+            return;
+        }
+        s.preset = Some(PresetProgress { name: name.to_string(), done, total });
+        drop(s);
+        let _ = self.events.send(Event::PresetProgress { name: name.to_string(), done, total });
+    }
+
+    async fn step_to(&self, kind: LevelKind, target: u8, on_step: &mut (dyn FnMut() + Send)) -> CommandResult {
+        let op = self.op_counter(kind).fetch_add(1, Ordering::SeqCst) + 1;
+        self.move_to(kind, op, target, on_step).await
+    }
+
+    async fn move_to(&self, kind: LevelKind, op: u64, target: u8, on_step: &mut (dyn FnMut() + Send)) -> CommandResult {
+        let counter = self.op_counter(kind);
+        let level = self.state.lock().unwrap().level(kind);
+        let target = target.min(level.max_steps);
+        level.check_target(kind, target)?;
+        if !level.is_calibrated() {
+            self.drive_to_floor(kind, op, on_step).await?;
+        }
+
+        loop {
+            if counter.load(Ordering::SeqCst) != op {
+                return Err(CommandError::Cancelled);
+            }
+            let current = self.state.lock().unwrap().level(kind).steps.unwrap_or(0);
+            if current == target {
+                return Ok(());
+            }
+            let cmd = if current < target { kind.up() } else { kind.down() };
+            self.step(cmd).await?;
+            on_step();
+        }
+    }
+
+    /// Steps the level to `target` over `duration`, timing the steps along `easing`; a step
+    /// that can't keep up just runs late. Calibrates first if needed, except that an
+    /// uncalibrated fade to 0 counts down from the top of the range at the fade's pace. Like
+    /// `set_level`, it is cancelled by a newer operation on the level, a manual step or `cancel`.
+    pub async fn fade_to(&self, kind: LevelKind, target: u8, duration: Duration, easing: Easing) -> CommandResult {
+        let op = self.op_counter(kind).fetch_add(1, Ordering::SeqCst) + 1;
+        self.fade(kind, op, target, duration, easing).await
+    }
+
+    pub async fn fade_out(&self, duration: Duration, easing: Easing) -> CommandResult {
+        self.fade_to(LevelKind::Volume, 0, duration, easing).await
+    }
+
+    /// Drops the volume to 0 at once, then fades up to `target`.
+    pub async fn fade_in(&self, target: u8, duration: Duration, easing: Easing) -> CommandResult {
+        let op = self.volume_op.fetch_add(1, Ordering::SeqCst) + 1;
+        self.state.lock().unwrap().volume.check_target(LevelKind::Volume, target)?;
+        self.move_to(LevelKind::Volume, op, 0, &mut || {}).await?;
+        self.fade(LevelKind::Volume, op, target, duration, easing).await
+    }
+
+    async fn fade(&self, kind: LevelKind, op: u64, target: u8, duration: Duration, easing: Easing) -> CommandResult {
+        let counter = self.op_counter(kind);
+        let level = self.state.lock().unwrap().level(kind);
+        let target = target.min(level.max_steps);
+        level.check_target(kind, target)?;
+        // Without a known level, only a fade to the floor can be paced: it ends there either way.
+        let blind = !level.is_calibrated() && target == 0;
+        if !level.is_calibrated() && !blind {
+            self.drive_to_floor(kind, op, &mut || {}).await?;
+        }
+        let start = match blind {
+            true => level.max_steps,
+            false => self.state.lock().unwrap().level(kind).steps.unwrap_or(0),
+        };
+        let mut position = start;
+        let begin = Instant::now();
+
+        loop {
+            if counter.load(Ordering::SeqCst) != op {
+                return Err(CommandError::Cancelled);
+            }
+            let current = match blind {
+                true => position,
+                false => self.state.lock().unwrap().level(kind).steps.unwrap_or(0),
+            };
+            if current == target {
+                if blind {
+                    self.state.lock().unwrap().level_mut(kind).set_floor();
+                }
+                return Ok(());
+            }
+            let t = match duration.is_zero() {
+                true => 1.0,
+                false => begin.elapsed().as_secs_f32() / duration.as_secs_f32(),
+            };
+            let wanted = start as f32 + (target as f32 - start as f32) * easing.apply(t);
+            // Only ever step towards the target, once the curve has passed the next step.
+            let cmd = match (current < target, current > target) {
+                (true, _) if wanted >= current as f32 + 1.0 => kind.up(),
+                (_, true) if wanted <= current as f32 - 1.0 => kind.down(),
+                _ => {
+                    sleep(FADE_TICK).await;
+                    continue;
+                }
+            };
+            self.step(cmd).await?;
+            position = position.saturating_sub(1);
+        }
+    }
+
+    fn op_counter(&self, kind: LevelKind) -> &AtomicU64 {
+        match kind {
+            LevelKind::Volume => &self.volume_op,
+            LevelKind::Bass => &self.bass_op,
+        }
+    }
+
+    async fn drive_to_floor(&self, kind: LevelKind, op: u64, on_step: &mut (dyn FnMut() + Send)) -> CommandResult {
+        let max_steps = self.state.lock().unwrap().level(kind).max_steps;
+        for _ in 0..max_steps {
+            if self.op_counter(kind).load(Ordering::SeqCst) != op {
+                return Err(CommandError::Cancelled);
+            }
+            self.step(kind.down()).await?;
+            on_step();
+        }
+        self.state.lock().unwrap().level_mut(kind).set_floor();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::connection::{ConnectionOptions, Z407Connection};
+    use crate::preset::LevelTarget;
+    use crate::simulator::{Simulator, SimulatorConfig, SimulatorHandle};
+    use crate::protocol::Response;
+    use crate::state::{Input, VOLUME_STEPS};
+
+    fn wait_until(what: &str, mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out waiting until {}", what);
+            thread::sleep(Duration::from_millis(2));
+        }
+    }
+
+    /// A connected simulator, polled often enough that a calibration takes well under a second.
+    fn simulated() -> (Z407Connection, SimulatorHandle) {
+        let (simulator, puck) = Simulator::new(SimulatorConfig::default());
+        let state = Z407State { scan_requested: true, ..Default::default() };
+        let options = ConnectionOptions { poll_interval: Duration::from_millis(1), ..Default::default() };
+        let conn = Z407Connection::spawn_with(state, simulator, options);
+        wait_until("connected", || conn.snapshot().connected);
+        (conn, puck)
+    }
+
+    fn preset_events(events: &mut broadcast::Receiver<Event>) -> Vec<Event> {
+        std::iter::from_fn(|| events.try_recv().ok())
+            .filter(|e| matches!(e, Event::PresetProgress { .. } | Event::PresetFinished { .. }))
+            .collect()
+    }
+
+    #[test]
+    fn applies_a_preset_and_reports_progress() {
+        let (conn, puck) = simulated();
+        let ctl = conn.controller();
+        let mut events = ctl.subscribe();
+        let preset = Preset { input: Some(Input::Aux), volume: Some(LevelTarget::Steps(10)), bass: Some(LevelTarget::Percent(25)) };
+
+        assert_eq!(conn.runtime().block_on(ctl.apply_preset("calls", &preset)), Ok(()));
+        assert_eq!((puck.input(), puck.volume(), puck.bass()), (Input::Aux, 10, 5));
+        let state = conn.snapshot();
+        assert_eq!((state.current_input, state.volume.steps, state.bass.steps), (Some(Input::Aux), Some(10), Some(5)));
+        assert_eq!(state.preset, None);
+
+        let events = preset_events(&mut events);
+        let progress: Vec<(u32, u32)> = events
+            .iter()
+            .filter_map(|e| match e {
+                Event::PresetProgress { done, total, .. } => Some((*done, *total)),
+                _ => None,
+            })
+            .collect();
+        // Switching input, then calibrating and stepping each level.
+        let total = 1 + 50 + 10 + 20 + 5;
+        assert_eq!(progress.first(), Some(&(0, total)));
+        assert_eq!(progress.last(), Some(&(total, total)));
+        assert!(progress.windows(2).all(|w| w[1].0 == w[0].0 + 1));
+        assert_eq!(events.last(), Some(&Event::PresetFinished { name: "calls".to_string(), error: None }));
+    }
+
+    #[test]
+    fn cancels_a_running_preset() {
+        let (conn, puck) = simulated();
+        let ctl = conn.controller();
+        let mut events = ctl.subscribe();
+        let preset = Preset { input: None, volume: Some(LevelTarget::Steps(40)), bass: None };
+
+        let running = conn.runtime().spawn({
+            let ctl = ctl.clone();
+            async move { ctl.apply_preset("loud", &preset).await }
+        });
+        wait_until("the preset is under way", || conn.snapshot().preset.is_some_and(|p| p.done >= 5));
+        ctl.cancel();
+        assert_eq!(conn.runtime().block_on(running).unwrap(), Err(CommandError::Cancelled));
+
+        assert_eq!(conn.snapshot().preset, None);
+        assert_ne!(puck.volume(), 40);
+        let finish = Event::PresetFinished { name: "loud".to_string(), error: Some("Cancelled".to_string()) };
+        assert_eq!(preset_events(&mut events).last(), Some(&finish));
+    }
+
+    fn volume_responses(events: &mut broadcast::Receiver<Event>) -> (usize, usize) {
+        let responses: Vec<Event> = std::iter::from_fn(|| events.try_recv().ok()).collect();
+        let count = |wanted: Response| responses.iter().filter(|e| matches!(e, Event::Response { response, .. } if *response == wanted)).count();
+        (count(Response::VolumeUp), count(Response::VolumeDown))
+    }
+
+    #[test]
+    fn fades_at_the_pace_asked_for() {
+        let (conn, puck) = simulated();
+        let ctl = conn.controller();
+        assert_eq!(conn.runtime().block_on(ctl.set_volume(0)), Ok(()));
+        let start = Instant::now();
+        assert_eq!(conn.runtime().block_on(ctl.fade_to(LevelKind::Volume, 10, Duration::from_millis(300), Easing::Linear)), Ok(()));
+        assert!(start.elapsed() >= Duration::from_millis(250), "took {:?}", start.elapsed());
+        assert_eq!((puck.volume(), conn.snapshot().volume.steps), (10, Some(10)));
+
+        assert_eq!(conn.runtime().block_on(ctl.fade_in(4, Duration::from_millis(50), Easing::EaseOut)), Ok(()));
+        assert_eq!(puck.volume(), 4);
+    }
+
+    #[test]
+    fn stops_a_fade_that_is_cancelled() {
+        let (conn, puck) = simulated();
+        let ctl = conn.controller();
+        assert_eq!(conn.runtime().block_on(ctl.set_volume(0)), Ok(()));
+        let fading = conn.runtime().spawn({
+            let ctl = ctl.clone();
+            async move { ctl.fade_to(LevelKind::Volume, 40, Duration::from_secs(2), Easing::Linear).await }
+        });
+        wait_until("the fade is under way", || conn.snapshot().volume.steps >= Some(3));
+        ctl.cancel();
+        assert_eq!(conn.runtime().block_on(fading).unwrap(), Err(CommandError::Cancelled));
+
+        let stopped = puck.volume();
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(puck.volume(), stopped);
+        assert!(stopped < 40);
+
+        // A manual step cancels one too.
+        let fading = conn.runtime().spawn({
+            let ctl = ctl.clone();
+            async move { ctl.fade_out(Duration::from_secs(2), Easing::Linear).await }
+        });
+        wait_until("the fade is under way", || conn.snapshot().volume.steps < Some(stopped));
+        ctl.send(Command::VolumeUp);
+        assert_eq!(conn.runtime().block_on(fading).unwrap(), Err(CommandError::Cancelled));
+    }
+
+    #[test]
+    fn ends_a_blind_fade_out_calibrated() {
+        let (conn, puck) = simulated();
+        let ctl = conn.controller();
+        let mut events = ctl.subscribe();
+        assert!(!conn.snapshot().volume.is_calibrated());
+
+        assert_eq!(conn.runtime().block_on(ctl.fade_out(Duration::from_millis(200), Easing::Linear)), Ok(()));
+        assert_eq!((puck.volume(), conn.snapshot().volume.steps), (0, Some(0)));
+        // Counted down from the top of the range, without a calibration first.
+        assert_eq!(volume_responses(&mut events), (0, VOLUME_STEPS as usize));
+    }
+}
@@ -0,0 +1,36 @@
+use serde::Serialize;
+
+use crate::handshake::HandshakeStep;
+use crate::level::LevelKind;
+use crate::protocol::Response;
+
+/// Everything the connection reports as it happens, for live dashboards and other subscribers.
+/// Serializes to JSON tagged by `event`, e.g. `{"event":"response","response":"volume_up","hex":"c002"}`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Scanning,
+    Connecting { device: String },
+    /// The handshake moved on to `step`.
+    Handshake { step: HandshakeStep },
+    Connected { device: String },
+    Response { response: Response, hex: String },
+    /// A handshaken link dropped.
+    Disconnected,
+    ConnectFailed { error: String },
+    Reconnecting { attempt: u32, delay_ms: u64 },
+    GaveUp { retries: u32 },
+    /// A preset confirmed another command, `done` of roughly `total`.
+    PresetProgress { name: String, done: u32, total: u32 },
+    /// The puck raised a level past its limit and the connection is stepping it back down.
+    LimitEnforced { level: LevelKind, limit: u8 },
+    /// A preset finished, failed or was cancelled; `error` is unset on success.
+    PresetFinished { name: String, error: Option<String> },
+}
+
+impl From<Response> for Event {
+    fn from(response: Response) -> Self {
+        let hex = hex::encode(response.encode());
+        Event::Response { response, hex }
+    }
+}
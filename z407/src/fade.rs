@@ -0,0 +1,100 @@
+//! Curves for spreading a level change over time; see `Z407Controller::fade_to`.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Easing {
+    Linear,
+    /// Slow start, fast finish.
+    EaseIn,
+    /// Fast start, slow finish.
+    EaseOut,
+    #[default]
+    EaseInOut,
+}
+
+impl Easing {
+    /// How far along the change should be at `t`, both from 0 to 1.
+    pub fn apply(self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Easing::Linear => t,
+            Easing::EaseIn => t * t,
+            Easing::EaseOut => 1.0 - (1.0 - t) * (1.0 - t),
+            Easing::EaseInOut => t * t * (3.0 - 2.0 * t),
+        }
+    }
+}
+
+/// Accepts the serde names, with `-` or `_`.
+impl FromStr for Easing {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().replace('-', "_").as_str() {
+            "linear" => Ok(Easing::Linear),
+            "ease_in" => Ok(Easing::EaseIn),
+            "ease_out" => Ok(Easing::EaseOut),
+            "ease_in_out" => Ok(Easing::EaseInOut),
+            _ => Err(anyhow!("Unknown easing {:?}, expected linear, ease-in, ease-out or ease-in-out", s)),
+        }
+    }
+}
+
+impl fmt::Display for Easing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Easing::Linear => write!(f, "linear"),
+            Easing::EaseIn => write!(f, "ease-in"),
+            Easing::EaseOut => write!(f, "ease-out"),
+            Easing::EaseInOut => write!(f, "ease-in-out"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EASINGS: [Easing; 4] = [Easing::Linear, Easing::EaseIn, Easing::EaseOut, Easing::EaseInOut];
+
+    #[test]
+    fn runs_from_start_to_finish() {
+        for easing in EASINGS {
+            assert_eq!(easing.apply(0.0), 0.0, "{}", easing);
+            assert_eq!(easing.apply(1.0), 1.0, "{}", easing);
+            assert_eq!((easing.apply(-0.5), easing.apply(1.5)), (0.0, 1.0), "{}", easing);
+        }
+    }
+
+    #[test]
+    fn never_turns_back() {
+        for easing in EASINGS {
+            let curve: Vec<f32> = (0..=100).map(|i| easing.apply(i as f32 / 100.0)).collect();
+            assert!(curve.windows(2).all(|w| w[1] >= w[0]), "{}", easing);
+        }
+    }
+
+    #[test]
+    fn shapes_each_curve() {
+        assert_eq!(Easing::Linear.apply(0.5), 0.5);
+        assert!(Easing::EaseIn.apply(0.25) < 0.25);
+        assert!(Easing::EaseOut.apply(0.25) > 0.25);
+        assert_eq!(Easing::EaseInOut.apply(0.5), 0.5);
+        assert!(Easing::EaseInOut.apply(0.1) < 0.1 && Easing::EaseInOut.apply(0.9) > 0.9);
+    }
+
+    #[test]
+    fn parses_names() {
+        for easing in EASINGS {
+            assert_eq!(easing.to_string().parse::<Easing>().unwrap(), easing);
+        }
+        assert_eq!("ease_in_out".parse::<Easing>().unwrap(), Easing::EaseInOut);
+        assert!("bounce".parse::<Easing>().is_err());
+    }
+}
@@ -0,0 +1,189 @@
+use std::fmt;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::time::{timeout_at, Instant};
+
+use crate::protocol::{Command, Response};
+use crate::transport::Z407Transport;
+
+/// The response the handshake is currently waiting for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HandshakeStep {
+    AwaitInitiated,
+    AwaitAcknowledged,
+    AwaitConnected,
+    Complete,
+}
+
+impl HandshakeStep {
+    pub fn expected(self) -> Option<Response> {
+        match self {
+            HandshakeStep::AwaitInitiated => Some(Response::Initiated),
+            HandshakeStep::AwaitAcknowledged => Some(Response::Acknowledged),
+            HandshakeStep::AwaitConnected => Some(Response::Connected),
+            HandshakeStep::Complete => None,
+        }
+    }
+
+    /// Moves to the next step if `resp` is the one this step expects.
+    pub fn advance(self, resp: &Response) -> HandshakeStep {
+        if self.expected().as_ref() != Some(resp) {
+            return self;
+        }
+        match self {
+            HandshakeStep::AwaitInitiated => HandshakeStep::AwaitAcknowledged,
+            HandshakeStep::AwaitAcknowledged => HandshakeStep::AwaitConnected,
+            HandshakeStep::AwaitConnected | HandshakeStep::Complete => HandshakeStep::Complete,
+        }
+    }
+}
+
+impl fmt::Display for HandshakeStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.expected() {
+            Some(resp) => write!(f, "waiting for {}", hex::encode(resp.encode())),
+            None => write!(f, "complete"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum HandshakeFailure {
+    Timeout,
+    LinkClosed,
+    Write(anyhow::Error),
+}
+
+#[derive(Debug)]
+pub struct HandshakeError {
+    pub step: HandshakeStep,
+    pub failure: HandshakeFailure,
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.failure {
+            HandshakeFailure::Timeout => write!(f, "Handshake timed out {}", self.step),
+            HandshakeFailure::LinkClosed => write!(f, "Link closed during handshake ({})", self.step),
+            HandshakeFailure::Write(e) => write!(f, "Handshake write failed ({}): {}", self.step, e),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+/// Runs INITIATE/ACKNOWLEDGE and waits for d40501, d40001 and d40003 in turn, allowing
+/// `step_timeout` for each. Every frame received meanwhile is passed to `on_response`, and
+/// `on_step` hears about each step reached.
+pub async fn perform<T: Z407Transport>(
+    transport: &mut T,
+    notifs: &mut UnboundedReceiver<Vec<u8>>,
+    step_timeout: Duration,
+    mut on_response: impl FnMut(Response),
+    mut on_step: impl FnMut(HandshakeStep),
+) -> Result<(), HandshakeError> {
+    let mut step = HandshakeStep::AwaitInitiated;
+
+    while step != HandshakeStep::Complete {
+        let command = match step {
+            HandshakeStep::AwaitInitiated => Some(Command::Initiate),
+            HandshakeStep::AwaitAcknowledged => Some(Command::Acknowledge),
+            _ => None,
+        };
+        if let Some(command) = command {
+            if let Err(e) = transport.write(command).await {
+                return Err(HandshakeError { step, failure: HandshakeFailure::Write(e) });
+            }
+        }
+
+        let deadline = Instant::now() + step_timeout;
+        let current = step;
+        while step == current {
+            match timeout_at(deadline, notifs.recv()).await {
+                Ok(Some(data)) => {
+                    let resp = Response::decode(&data);
+                    step = step.advance(&resp);
+                    on_response(resp);
+                    if step != current {
+                        on_step(step);
+                    }
+                }
+                Ok(None) => return Err(HandshakeError { step, failure: HandshakeFailure::LinkClosed }),
+                Err(_) => return Err(HandshakeError { step, failure: HandshakeFailure::Timeout }),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{DeviceInfo, MemoryTransport};
+
+    const STEP_TIMEOUT: Duration = Duration::from_millis(100);
+
+    /// A speaker that answers the handshake up to, but not including, `stop_at`.
+    async fn run(stop_at: Option<Response>) -> (Result<(), HandshakeError>, Vec<HandshakeStep>) {
+        let (mut transport, handle) = MemoryTransport::new();
+        handle.set_responder(move |cmd| {
+            let replies = match cmd {
+                Command::Initiate => vec![Response::Initiated],
+                Command::Acknowledge => vec![Response::Acknowledged, Response::Connected],
+                _ => vec![],
+            };
+            replies.into_iter().take_while(|r| Some(r) != stop_at.as_ref()).map(|r| r.encode()).collect()
+        });
+        let device = DeviceInfo { id: "memory".to_string(), name: None, rssi: None };
+        transport.connect(&device).await.unwrap();
+        let mut notifs = transport.subscribe().await.unwrap();
+        let mut steps = Vec::new();
+        let result = perform(&mut transport, &mut notifs, STEP_TIMEOUT, |_| {}, |step| steps.push(step)).await;
+        (result, steps)
+    }
+
+    #[tokio::test]
+    async fn completes_when_every_reply_arrives() {
+        let (result, steps) = run(None).await;
+        assert!(result.is_ok());
+        let expected = [HandshakeStep::AwaitAcknowledged, HandshakeStep::AwaitConnected, HandshakeStep::Complete];
+        assert_eq!(steps, expected);
+    }
+
+    #[tokio::test]
+    async fn times_out_naming_the_missing_reply() {
+        let cases = [
+            (Response::Initiated, HandshakeStep::AwaitInitiated, "d40501"),
+            (Response::Acknowledged, HandshakeStep::AwaitAcknowledged, "d40001"),
+            (Response::Connected, HandshakeStep::AwaitConnected, "d40003"),
+        ];
+        for (missing, step, hex) in cases {
+            let error = run(Some(missing)).await.0.unwrap_err();
+            assert_eq!(error.step, step);
+            assert!(matches!(error.failure, HandshakeFailure::Timeout));
+            assert_eq!(error.to_string(), format!("Handshake timed out waiting for {}", hex));
+        }
+    }
+
+    #[tokio::test]
+    async fn ignores_unrelated_frames() {
+        let (mut transport, handle) = MemoryTransport::new();
+        handle.set_responder(|cmd| match cmd {
+            Command::Initiate => vec![vec![0xc0, 0x02], Response::Initiated.encode()],
+            Command::Acknowledge => vec![Response::Acknowledged.encode(), vec![0xcf, 0x05], Response::Connected.encode()],
+            _ => vec![],
+        });
+        let device = DeviceInfo { id: "memory".to_string(), name: None, rssi: None };
+        transport.connect(&device).await.unwrap();
+        let mut notifs = transport.subscribe().await.unwrap();
+        let mut seen = Vec::new();
+        let result = perform(&mut transport, &mut notifs, STEP_TIMEOUT, |r| seen.push(r), |_| {}).await;
+        assert!(result.is_ok());
+        assert_eq!(seen.len(), 5);
+        assert_eq!(handle.written(), [Command::Initiate, Command::Acknowledge]);
+    }
+}
@@ -0,0 +1,184 @@
+//! Line-delimited JSON protocol spoken by `z407d` on its Unix socket. Each request is one JSON
+//! object on its own line and is answered by exactly one response line carrying the same `id`.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::fade::Easing;
+use crate::level::{Level, LevelKind};
+use crate::preset::PresetProgress;
+use crate::protocol::Command;
+use crate::state::{Input, Z407State};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum DaemonRequest {
+    /// Send a raw command `steps` times (default 1), each waiting for its confirmation.
+    Command {
+        command: Command,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        steps: Option<u32>,
+    },
+    SetLevel { level: LevelKind, target: u8 },
+    Calibrate { level: LevelKind },
+    /// Step the level to `target` over `over_ms`; see `Z407Controller::fade_to`.
+    Fade {
+        level: LevelKind,
+        target: u8,
+        over_ms: u64,
+        #[serde(default)]
+        easing: Easing,
+    },
+    /// Drop the volume to 0, then fade it up to `target` over `over_ms`.
+    FadeIn {
+        target: u8,
+        over_ms: u64,
+        #[serde(default)]
+        easing: Easing,
+    },
+    State,
+    /// Start a connection attempt now, skipping any reconnect backoff.
+    Connect,
+    /// Apply a preset from the daemon's config.
+    Preset { name: String },
+    /// Stop any running preset, `set_level`, `calibrate` or fade.
+    Cancel,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequestEnvelope {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    /// Speaker name, group or `all`; every speaker when absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speaker: Option<String>,
+    #[serde(flatten)]
+    pub request: DaemonRequest,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DaemonResponse {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Set when the request targeted a single speaker.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<StateSnapshot>,
+    /// Set instead of `state` when the request targeted several, keyed by speaker name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub states: Option<BTreeMap<String, StateSnapshot>>,
+}
+
+impl DaemonResponse {
+    pub fn ok(id: Option<u64>) -> Self {
+        Self { id, ok: true, error: None, state: None, states: None }
+    }
+
+    pub fn error(id: Option<u64>, error: impl ToString) -> Self {
+        Self { id, ok: false, error: Some(error.to_string()), state: None, states: None }
+    }
+
+    /// Whether every targeted speaker was connected.
+    pub fn connected(&self) -> bool {
+        match (&self.state, &self.states) {
+            (Some(state), _) => state.connected,
+            (None, Some(states)) => !states.is_empty() && states.values().all(|s| s.connected),
+            (None, None) => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LevelSnapshot {
+    pub steps: Option<u8>,
+    pub max_steps: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u8>,
+}
+
+impl From<Level> for LevelSnapshot {
+    fn from(level: Level) -> Self {
+        Self { steps: level.steps, max_steps: level.max_steps, limit: level.limit }
+    }
+}
+
+/// The serializable part of `Z407State`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StateSnapshot {
+    pub connected: bool,
+    #[serde(default)]
+    pub device: Option<String>,
+    pub input: Option<Input>,
+    pub volume: LevelSnapshot,
+    pub bass: LevelSnapshot,
+    pub last_error: Option<String>,
+    pub retry_attempt: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preset: Option<PresetProgress>,
+}
+
+impl From<&Z407State> for StateSnapshot {
+    fn from(s: &Z407State) -> Self {
+        Self {
+            connected: s.connected,
+            device: s.device.clone(),
+            input: s.current_input,
+            volume: s.volume.into(),
+            bass: s.bass.into(),
+            last_error: s.last_error.clone(),
+            retry_attempt: s.retry.as_ref().map(|r| r.attempt),
+            preset: s.preset.clone(),
+        }
+    }
+}
+
+/// `z407.sock` in `$XDG_RUNTIME_DIR`, or in the temp dir without one. `z407d` makes the socket
+/// accessible to its own user only.
+pub fn default_socket_path() -> PathBuf {
+    let dir = env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from).unwrap_or_else(env::temp_dir);
+    dir.join("z407.sock")
+}
+
+/// Blocking client for scripts and the CLI.
+pub struct DaemonClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    next_id: u64,
+}
+
+impl DaemonClient {
+    pub fn connect(path: &Path) -> Result<Self> {
+        let writer = UnixStream::connect(path)
+            .map_err(|e| anyhow!("Cannot reach z407d at {}: {}", path.display(), e))?;
+        let reader = BufReader::new(writer.try_clone()?);
+        Ok(Self { reader, writer, next_id: 1 })
+    }
+
+    pub fn request(&mut self, request: DaemonRequest) -> Result<DaemonResponse> {
+        self.request_to(None, request)
+    }
+
+    /// `request` for one speaker, a group or `all`.
+    pub fn request_to(&mut self, speaker: Option<&str>, request: DaemonRequest) -> Result<DaemonResponse> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let speaker = speaker.map(str::to_string);
+        let mut line = serde_json::to_string(&RequestEnvelope { id: Some(id), speaker, request })?;
+        line.push('\n');
+        self.writer.write_all(line.as_bytes())?;
+
+        let mut reply = String::new();
+        if self.reader.read_line(&mut reply)? == 0 {
+            return Err(anyhow!("z407d closed the connection"));
+        }
+        Ok(serde_json::from_str(&reply)?)
+    }
+}
@@ -0,0 +1,167 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::ack::{CommandError, CommandResult};
+use crate::protocol::Command;
+
+/// A level the speaker only exposes through relative steps. `steps` stays `None` until a
+/// calibration has driven the level to its floor; from then on every confirmed step moves it,
+/// until the link drops and the puck could have moved it unseen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Level {
+    pub steps: Option<u8>,
+    /// Length of the range. The speaker can't report it, so it comes from `[levels]`.
+    pub max_steps: u8,
+    /// Highest step count the level may be raised to, from `[limits]`.
+    pub limit: Option<u8>,
+}
+
+impl Level {
+    pub fn new(max_steps: u8) -> Self {
+        Self { steps: None, max_steps, limit: None }
+    }
+
+    /// The limit, or `max_steps` without one.
+    pub fn ceiling(&self) -> u8 {
+        self.limit.unwrap_or(self.max_steps).min(self.max_steps)
+    }
+
+    /// Steps above the limit, once calibrated.
+    pub fn excess(&self) -> u8 {
+        match (self.steps, self.limit) {
+            (Some(steps), Some(limit)) => steps.saturating_sub(limit),
+            _ => 0,
+        }
+    }
+
+    /// Refuses an up step at or past the limit, or any up step while uncalibrated, since then
+    /// the limit can't be told apart. Calibrating would take the level to its floor, so that is
+    /// left to an explicit `calibrate` or `set_level`.
+    pub fn check_raise(&self, kind: LevelKind) -> CommandResult {
+        match (self.limit, self.steps) {
+            (None, _) => Ok(()),
+            (Some(_), None) => Err(CommandError::Uncalibrated(kind)),
+            (Some(limit), Some(steps)) if steps >= limit => Err(CommandError::AtLimit { kind, limit }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Refuses an absolute target above the limit.
+    pub fn check_target(&self, kind: LevelKind, target: u8) -> CommandResult {
+        match self.limit {
+            Some(limit) if target > limit => Err(CommandError::AtLimit { kind, limit }),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn is_calibrated(&self) -> bool {
+        self.steps.is_some()
+    }
+
+    pub fn step(&mut self, up: bool) {
+        if let Some(steps) = self.steps {
+            self.steps = Some(if up { (steps + 1).min(self.max_steps) } else { steps.saturating_sub(1) });
+        }
+    }
+
+    pub fn set_floor(&mut self) {
+        self.steps = Some(0);
+    }
+
+    pub fn percent(&self) -> Option<f32> {
+        self.steps.map(|s| s as f32 * 100.0 / self.max_steps.max(1) as f32)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LevelKind {
+    Volume,
+    Bass,
+}
+
+impl LevelKind {
+    pub fn up(self) -> Command {
+        match self {
+            LevelKind::Volume => Command::VolumeUp,
+            LevelKind::Bass => Command::BassUp,
+        }
+    }
+
+    pub fn down(self) -> Command {
+        match self {
+            LevelKind::Volume => Command::VolumeDown,
+            LevelKind::Bass => Command::BassDown,
+        }
+    }
+
+    /// The level `cmd` steps up or down, if any.
+    pub fn stepped_by(cmd: Command) -> Option<LevelKind> {
+        match cmd {
+            Command::VolumeUp | Command::VolumeDown => Some(LevelKind::Volume),
+            Command::BassUp | Command::BassDown => Some(LevelKind::Bass),
+            _ => None,
+        }
+    }
+
+    /// The level `cmd` raises, if any.
+    pub fn raised_by(cmd: Command) -> Option<LevelKind> {
+        match cmd {
+            Command::VolumeUp => Some(LevelKind::Volume),
+            Command::BassUp => Some(LevelKind::Bass),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for LevelKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LevelKind::Volume => write!(f, "Volume"),
+            LevelKind::Bass => write!(f, "Bass"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level(steps: Option<u8>, limit: Option<u8>) -> Level {
+        Level { steps, limit, ..Level::new(50) }
+    }
+
+    #[test]
+    fn raises_up_to_the_limit() {
+        let at_limit = Err(CommandError::AtLimit { kind: LevelKind::Volume, limit: 30 });
+        assert_eq!(level(Some(29), Some(30)).check_raise(LevelKind::Volume), Ok(()));
+        assert_eq!(level(Some(30), Some(30)).check_raise(LevelKind::Volume), at_limit);
+        assert_eq!(level(Some(40), Some(30)).check_raise(LevelKind::Volume), at_limit);
+        assert_eq!(level(Some(50), None).check_raise(LevelKind::Volume), Ok(()));
+    }
+
+    #[test]
+    fn refuses_raising_a_limited_level_until_calibrated() {
+        assert_eq!(level(None, Some(30)).check_raise(LevelKind::Bass), Err(CommandError::Uncalibrated(LevelKind::Bass)));
+        assert_eq!(level(None, None).check_raise(LevelKind::Bass), Ok(()));
+        assert_eq!(level(Some(0), Some(30)).check_raise(LevelKind::Bass), Ok(()));
+    }
+
+    #[test]
+    fn refuses_targets_above_the_limit() {
+        let limited = level(None, Some(30));
+        assert_eq!(limited.check_target(LevelKind::Bass, 30), Ok(()));
+        assert_eq!(limited.check_target(LevelKind::Bass, 31), Err(CommandError::AtLimit { kind: LevelKind::Bass, limit: 30 }));
+        assert_eq!(level(None, None).check_target(LevelKind::Bass, 50), Ok(()));
+    }
+
+    #[test]
+    fn measures_the_excess_once_calibrated() {
+        assert_eq!(level(Some(35), Some(30)).excess(), 5);
+        assert_eq!(level(Some(30), Some(30)).excess(), 0);
+        assert_eq!(level(Some(10), Some(30)).excess(), 0);
+        assert_eq!(level(None, Some(30)).excess(), 0);
+        assert_eq!(level(Some(50), None).excess(), 0);
+    }
+}
@@ -0,0 +1,38 @@
+pub mod ack;
+pub mod config;
+pub mod connection;
+pub mod controller;
+pub mod event;
+pub mod fade;
+pub mod handshake;
+#[cfg(unix)]
+pub mod ipc;
+pub mod level;
+pub mod preset;
+pub mod protocol;
+pub mod reconnect;
+pub mod schedule;
+pub mod scheduler;
+pub mod simulator;
+pub mod speakers;
+pub mod state;
+pub mod transport;
+pub mod triggers;
+
+pub use ack::{AckPolicy, CommandError, CommandResult};
+pub use config::Config;
+pub use connection::{ConnectionOptions, Z407Connection};
+pub use controller::Z407Controller;
+pub use event::Event;
+pub use fade::Easing;
+pub use level::{Level, LevelKind};
+pub use preset::{LevelTarget, Preset, PresetProgress};
+pub use protocol::{Command, Response};
+pub use reconnect::ReconnectPolicy;
+pub use schedule::{Rule, Schedule};
+pub use scheduler::{Clock, MockClock, Scheduler, SystemClock};
+pub use simulator::{Simulator, SimulatorConfig, SimulatorHandle};
+pub use speakers::{Fleet, Speaker, Speakers};
+pub use state::{Input, Z407State};
+pub use transport::{DeviceFilter, Z407Transport};
+pub use triggers::Trigger;
@@ -0,0 +1,176 @@
+//! Named combinations of input, volume and bass, stored in the config under `[presets.<name>]`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::level::Level;
+use crate::state::{Input, Z407State};
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Preset {
+    pub input: Option<Input>,
+    pub volume: Option<LevelTarget>,
+    pub bass: Option<LevelTarget>,
+}
+
+/// A level as a step count (`20`) or a share of the level's range (`"60%"`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LevelTarget {
+    Steps(u8),
+    Percent(u8),
+}
+
+impl LevelTarget {
+    pub fn steps(self, max_steps: u8) -> u8 {
+        match self {
+            LevelTarget::Steps(steps) => steps.min(max_steps),
+            LevelTarget::Percent(percent) => (percent as f32 * max_steps as f32 / 100.0).round() as u8,
+        }
+    }
+}
+
+impl fmt::Display for LevelTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LevelTarget::Steps(steps) => write!(f, "{}", steps),
+            LevelTarget::Percent(percent) => write!(f, "{}%", percent),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for LevelTarget {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TargetVisitor;
+
+        impl Visitor<'_> for TargetVisitor {
+            type Value = LevelTarget;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a step count or a percentage like \"60%\"")
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<LevelTarget, E> {
+                u8::try_from(v).map(LevelTarget::Steps).map_err(|_| E::custom(format!("step count {} out of range", v)))
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<LevelTarget, E> {
+                self.visit_i64(v as i64)
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<LevelTarget, E> {
+                let percent = v.trim().strip_suffix('%').and_then(|p| p.trim().parse::<u8>().ok());
+                match percent {
+                    Some(percent) if percent <= 100 => Ok(LevelTarget::Percent(percent)),
+                    _ => Err(E::custom(format!("invalid level {:?}, expected e.g. \"60%\"", v))),
+                }
+            }
+        }
+
+        deserializer.deserialize_any(TargetVisitor)
+    }
+}
+
+impl Preset {
+    pub fn is_empty(&self) -> bool {
+        self.input.is_none() && self.volume.is_none() && self.bass.is_none()
+    }
+
+    /// Roughly how many confirmed commands applying this preset to `state` takes, counting a
+    /// full calibration for each level that still needs one.
+    pub fn estimate_steps(&self, state: &Z407State) -> u32 {
+        let input = match self.input {
+            Some(input) if state.current_input != Some(input) => 1,
+            _ => 0,
+        };
+        let level = |target: Option<LevelTarget>, level: Level| match (target, level.steps) {
+            (None, _) => 0,
+            (Some(target), Some(steps)) => target.steps(level.max_steps).abs_diff(steps) as u32,
+            (Some(target), None) => level.max_steps as u32 + target.steps(level.max_steps) as u32,
+        };
+        input + level(self.volume, state.volume) + level(self.bass, state.bass)
+    }
+}
+
+impl fmt::Display for Preset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(input) = self.input {
+            parts.push(input.to_string());
+        }
+        if let Some(volume) = self.volume {
+            parts.push(format!("vol {}", volume));
+        }
+        if let Some(bass) = self.bass {
+            parts.push(format!("bass {}", bass));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+pub fn find<'a>(presets: &'a BTreeMap<String, Preset>, name: &str) -> Result<&'a Preset> {
+    presets.get(name).ok_or_else(|| {
+        let known: Vec<&str> = presets.keys().map(String::as_str).collect();
+        match known.is_empty() {
+            true => anyhow!("Unknown preset {:?}; none are configured", name),
+            false => anyhow!("Unknown preset {:?} (known: {})", name, known.join(", ")),
+        }
+    })
+}
+
+/// How far a running preset has got, in confirmed commands.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PresetProgress {
+    pub name: String,
+    pub done: u32,
+    pub total: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(toml: &str) -> Result<LevelTarget, toml::de::Error> {
+        #[derive(Deserialize)]
+        struct Holder {
+            level: LevelTarget,
+        }
+        toml::from_str::<Holder>(&format!("level = {}", toml)).map(|h| h.level)
+    }
+
+    #[test]
+    fn reads_steps_and_percentages() {
+        assert_eq!(target("20").unwrap(), LevelTarget::Steps(20));
+        assert_eq!(target("\"60%\"").unwrap(), LevelTarget::Percent(60));
+        assert_eq!(target("\" 5 % \"").unwrap(), LevelTarget::Percent(5));
+        for bad in ["-1", "256", "\"101%\"", "\"60\"", "\"loud\"", "1.5"] {
+            assert!(target(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn converts_targets_to_steps() {
+        assert_eq!(LevelTarget::Percent(60).steps(50), 30);
+        assert_eq!(LevelTarget::Percent(100).steps(20), 20);
+        assert_eq!(LevelTarget::Percent(3).steps(20), 1);
+        assert_eq!(LevelTarget::Steps(60).steps(50), 50);
+    }
+
+    #[test]
+    fn estimates_the_steps_to_apply() {
+        let preset = Preset { input: Some(Input::Aux), volume: Some(LevelTarget::Steps(30)), bass: Some(LevelTarget::Percent(50)) };
+        let mut state = Z407State { current_input: Some(Input::Aux), ..Default::default() };
+        state.volume.steps = Some(20);
+        // Volume 10 steps away; bass calibrates over its 20 steps, then climbs 10.
+        assert_eq!(preset.estimate_steps(&state), 10 + 20 + 10);
+        state.current_input = Some(Input::Usb);
+        state.bass.steps = Some(10);
+        assert_eq!(preset.estimate_steps(&state), 1 + 10);
+        assert_eq!(Preset { input: None, volume: None, bass: None }.estimate_steps(&state), 0);
+    }
+}
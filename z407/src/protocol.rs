@@ -0,0 +1,307 @@
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Command {
+    Initiate,
+    Acknowledge,
+    VolumeUp,
+    VolumeDown,
+    BassUp,
+    BassDown,
+    PlayPause,
+    NextTrack,
+    PrevTrack,
+    SwitchBluetooth,
+    SwitchAux,
+    SwitchUsb,
+    Sound1,
+    Sound2,
+    Sound3,
+    Unknown1,
+    Pairing,
+    FactoryReset,
+}
+
+impl Command {
+    pub const ALL: [Command; 18] = [
+        Command::Initiate,
+        Command::Acknowledge,
+        Command::VolumeUp,
+        Command::VolumeDown,
+        Command::BassUp,
+        Command::BassDown,
+        Command::PlayPause,
+        Command::NextTrack,
+        Command::PrevTrack,
+        Command::SwitchBluetooth,
+        Command::SwitchAux,
+        Command::SwitchUsb,
+        Command::Sound1,
+        Command::Sound2,
+        Command::Sound3,
+        Command::Unknown1,
+        Command::Pairing,
+        Command::FactoryReset,
+    ];
+
+    pub fn encode(self) -> [u8; 2] {
+        match self {
+            Command::Initiate => [0x84, 0x05],
+            Command::Acknowledge => [0x84, 0x00],
+            Command::VolumeUp => [0x80, 0x02],
+            Command::VolumeDown => [0x80, 0x03],
+            Command::BassUp => [0x80, 0x00],
+            Command::BassDown => [0x80, 0x01],
+            Command::PlayPause => [0x80, 0x04],
+            Command::NextTrack => [0x80, 0x05],
+            Command::PrevTrack => [0x80, 0x06],
+            Command::SwitchBluetooth => [0x81, 0x01],
+            Command::SwitchAux => [0x81, 0x02],
+            Command::SwitchUsb => [0x81, 0x03],
+            Command::Sound1 => [0x85, 0x01],
+            Command::Sound2 => [0x85, 0x02],
+            Command::Sound3 => [0x85, 0x03],
+            Command::Unknown1 => [0x85, 0x00],
+            Command::Pairing => [0x82, 0x00],
+            Command::FactoryReset => [0x83, 0x00],
+        }
+    }
+
+    /// Sent by the connection itself to open a link; repeating one mid-session restarts it.
+    pub fn is_handshake(self) -> bool {
+        matches!(self, Command::Initiate | Command::Acknowledge)
+    }
+
+    /// The notification the speaker sends back once it has carried out this command.
+    pub fn confirmation(self) -> Response {
+        match self {
+            Command::Initiate => Response::Initiated,
+            Command::Acknowledge => Response::Acknowledged,
+            Command::VolumeUp => Response::VolumeUp,
+            Command::VolumeDown => Response::VolumeDown,
+            Command::BassUp => Response::BassUp,
+            Command::BassDown => Response::BassDown,
+            Command::PlayPause => Response::PlayPause,
+            Command::NextTrack => Response::NextTrack,
+            Command::PrevTrack => Response::PrevTrack,
+            Command::SwitchBluetooth => Response::SwitchBluetooth,
+            Command::SwitchAux => Response::SwitchAux,
+            Command::SwitchUsb => Response::SwitchUsb,
+            Command::Sound1 => Response::Sound1,
+            Command::Sound2 => Response::Sound2,
+            Command::Sound3 => Response::Sound3,
+            Command::Unknown1 => Response::Unknown1,
+            Command::Pairing => Response::Pairing,
+            Command::FactoryReset => Response::FactoryReset,
+        }
+    }
+}
+
+impl TryFrom<&[u8]> for Command {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        Command::ALL
+            .into_iter()
+            .find(|c| c.encode() == bytes)
+            .ok_or_else(|| anyhow!("Unknown command frame: {}", hex::encode(bytes)))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Response {
+    Initiated,
+    Acknowledged,
+    Connected,
+    BassUp,
+    BassDown,
+    VolumeUp,
+    VolumeDown,
+    PlayPause,
+    NextTrack,
+    PrevTrack,
+    SwitchBluetooth,
+    SwitchAux,
+    SwitchUsb,
+    Pairing,
+    FactoryReset,
+    Sound1,
+    Sound2,
+    Sound3,
+    Unknown1,
+    SwitchedBluetooth,
+    SwitchedAux,
+    SwitchedUsb,
+    Unknown(Vec<u8>),
+}
+
+impl Response {
+    pub fn decode(bytes: &[u8]) -> Response {
+        match bytes {
+            [0xd4, 0x05, 0x01] => Response::Initiated,
+            [0xd4, 0x00, 0x01] => Response::Acknowledged,
+            [0xd4, 0x00, 0x03] => Response::Connected,
+            [0xc0, 0x00] => Response::BassUp,
+            [0xc0, 0x01] => Response::BassDown,
+            [0xc0, 0x02] => Response::VolumeUp,
+            [0xc0, 0x03] => Response::VolumeDown,
+            [0xc0, 0x04] => Response::PlayPause,
+            [0xc0, 0x05] => Response::NextTrack,
+            [0xc0, 0x06] => Response::PrevTrack,
+            [0xc1, 0x01] => Response::SwitchBluetooth,
+            [0xc1, 0x02] => Response::SwitchAux,
+            [0xc1, 0x03] => Response::SwitchUsb,
+            [0xc2, 0x00] => Response::Pairing,
+            [0xc3, 0x00] => Response::FactoryReset,
+            [0xc5, 0x03] => Response::Sound1,
+            [0xc5, 0x02] => Response::Sound2,
+            [0xc5, 0x01] => Response::Sound3,
+            [0xc5, 0x00] => Response::Unknown1,
+            [0xcf, 0x04] => Response::SwitchedBluetooth,
+            [0xcf, 0x05] => Response::SwitchedAux,
+            [0xcf, 0x06] => Response::SwitchedUsb,
+            other => Response::Unknown(other.to_vec()),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Response::Initiated => vec![0xd4, 0x05, 0x01],
+            Response::Acknowledged => vec![0xd4, 0x00, 0x01],
+            Response::Connected => vec![0xd4, 0x00, 0x03],
+            Response::BassUp => vec![0xc0, 0x00],
+            Response::BassDown => vec![0xc0, 0x01],
+            Response::VolumeUp => vec![0xc0, 0x02],
+            Response::VolumeDown => vec![0xc0, 0x03],
+            Response::PlayPause => vec![0xc0, 0x04],
+            Response::NextTrack => vec![0xc0, 0x05],
+            Response::PrevTrack => vec![0xc0, 0x06],
+            Response::SwitchBluetooth => vec![0xc1, 0x01],
+            Response::SwitchAux => vec![0xc1, 0x02],
+            Response::SwitchUsb => vec![0xc1, 0x03],
+            Response::Pairing => vec![0xc2, 0x00],
+            Response::FactoryReset => vec![0xc3, 0x00],
+            Response::Sound1 => vec![0xc5, 0x03],
+            Response::Sound2 => vec![0xc5, 0x02],
+            Response::Sound3 => vec![0xc5, 0x01],
+            Response::Unknown1 => vec![0xc5, 0x00],
+            Response::SwitchedBluetooth => vec![0xcf, 0x04],
+            Response::SwitchedAux => vec![0xcf, 0x05],
+            Response::SwitchedUsb => vec![0xcf, 0x06],
+            Response::Unknown(bytes) => bytes.clone(),
+        }
+    }
+}
+
+impl From<&[u8]> for Response {
+    fn from(bytes: &[u8]) -> Self {
+        Response::decode(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_every_command() {
+        for command in Command::ALL {
+            let frame = command.encode();
+            assert_eq!(Command::try_from(&frame[..]).unwrap(), command);
+        }
+        let mut frames: Vec<[u8; 2]> = Command::ALL.iter().map(|c| c.encode()).collect();
+        frames.sort();
+        frames.dedup();
+        assert_eq!(frames.len(), Command::ALL.len());
+    }
+
+    #[test]
+    fn encodes_the_command_reference() {
+        let table = [
+            (Command::Initiate, [0x84, 0x05]),
+            (Command::Acknowledge, [0x84, 0x00]),
+            (Command::VolumeUp, [0x80, 0x02]),
+            (Command::VolumeDown, [0x80, 0x03]),
+            (Command::BassUp, [0x80, 0x00]),
+            (Command::BassDown, [0x80, 0x01]),
+            (Command::PlayPause, [0x80, 0x04]),
+            (Command::NextTrack, [0x80, 0x05]),
+            (Command::PrevTrack, [0x80, 0x06]),
+            (Command::SwitchBluetooth, [0x81, 0x01]),
+            (Command::SwitchAux, [0x81, 0x02]),
+            (Command::SwitchUsb, [0x81, 0x03]),
+            (Command::Sound1, [0x85, 0x01]),
+            (Command::Sound2, [0x85, 0x02]),
+            (Command::Sound3, [0x85, 0x03]),
+            (Command::Unknown1, [0x85, 0x00]),
+            (Command::Pairing, [0x82, 0x00]),
+            (Command::FactoryReset, [0x83, 0x00]),
+        ];
+        assert_eq!(table.len(), Command::ALL.len());
+        for (command, frame) in table {
+            assert_eq!(command.encode(), frame, "{:?}", command);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_and_misshapen_frames() {
+        for frame in [&[][..], &[0x80], &[0x80, 0x02, 0x00], &[0x80, 0x07], &[0xc0, 0x02], &[0x86, 0x00]] {
+            assert!(Command::try_from(frame).is_err(), "{:02x?}", frame);
+        }
+        let e = Command::try_from(&[0x80, 0x07][..]).unwrap_err();
+        assert_eq!(e.to_string(), "Unknown command frame: 8007");
+    }
+
+    /// The README's Response Reference, hex as written there.
+    const RESPONSES: [(Response, &str); 22] = [
+        (Response::Initiated, "d40501"),
+        (Response::Acknowledged, "d40001"),
+        (Response::Connected, "d40003"),
+        (Response::BassUp, "c000"),
+        (Response::BassDown, "c001"),
+        (Response::VolumeUp, "c002"),
+        (Response::VolumeDown, "c003"),
+        (Response::PlayPause, "c004"),
+        (Response::NextTrack, "c005"),
+        (Response::PrevTrack, "c006"),
+        (Response::SwitchBluetooth, "c101"),
+        (Response::SwitchAux, "c102"),
+        (Response::SwitchUsb, "c103"),
+        (Response::Pairing, "c200"),
+        (Response::FactoryReset, "c300"),
+        (Response::Sound1, "c503"),
+        (Response::Sound2, "c502"),
+        (Response::Sound3, "c501"),
+        (Response::Unknown1, "c500"),
+        (Response::SwitchedBluetooth, "cf04"),
+        (Response::SwitchedAux, "cf05"),
+        (Response::SwitchedUsb, "cf06"),
+    ];
+
+    #[test]
+    fn decodes_and_encodes_the_response_reference() {
+        for (response, hex) in RESPONSES {
+            let bytes = hex::decode(hex).unwrap();
+            assert_eq!(Response::decode(&bytes), response, "{}", hex);
+            assert_eq!(response.encode(), bytes, "{:?}", response);
+        }
+    }
+
+    #[test]
+    fn confirms_the_chimes_in_reverse() {
+        assert_eq!(Command::Sound1.confirmation().encode(), [0xc5, 0x03]);
+        assert_eq!(Command::Sound3.confirmation().encode(), [0xc5, 0x01]);
+    }
+
+    #[test]
+    fn keeps_unknown_responses_whole() {
+        for bytes in [&[][..], &[0xc0], &[0xc0, 0x07], &[0xd4, 0x05, 0x02], &[0xc0, 0x02, 0x00]] {
+            let response = Response::decode(bytes);
+            assert_eq!(response, Response::Unknown(bytes.to_vec()));
+            assert_eq!(response.encode(), bytes);
+        }
+    }
+}
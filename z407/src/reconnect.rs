@@ -0,0 +1,101 @@
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: f64,
+    /// Fraction of the delay that is randomly added or removed, e.g. 0.2 for ±20%.
+    pub jitter: f64,
+    /// Give up after this many consecutive retries; `None` retries forever.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Delay before retry number `attempt` (starting at 1), without jitter.
+    pub fn base_delay(&self, attempt: u32) -> Duration {
+        let exp = self.multiplier.powi(attempt.saturating_sub(1).min(i32::MAX as u32) as i32);
+        let secs = (self.initial_delay.as_secs_f64() * exp).min(self.max_delay.as_secs_f64());
+        Duration::from_secs_f64(secs)
+    }
+
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let base = self.base_delay(attempt).as_secs_f64();
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let factor = if jitter > 0.0 { rand::thread_rng().gen_range(-jitter..=jitter) } else { 0.0 };
+        Duration::from_secs_f64(base * (1.0 + factor))
+    }
+
+    pub fn exhausted(&self, attempt: u32) -> bool {
+        self.max_attempts.is_some_and(|max| attempt > max)
+    }
+}
+
+/// A pending automatic reconnect, published in `Z407State` so the UI can show it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryState {
+    pub attempt: u32,
+    pub retry_at: Instant,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(jitter: f64, max_attempts: Option<u32>) -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            multiplier: 2.0,
+            jitter,
+            max_attempts,
+        }
+    }
+
+    #[test]
+    fn grows_the_delay_up_to_the_cap() {
+        let policy = policy(0.0, None);
+        let delays: Vec<_> = (0..=6).map(|attempt| policy.base_delay(attempt).as_millis()).collect();
+        assert_eq!(delays, [100, 100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(policy.base_delay(u32::MAX), Duration::from_secs(1));
+        assert_eq!(policy.delay(3), Duration::from_millis(400));
+    }
+
+    #[test]
+    fn keeps_the_jitter_within_bounds() {
+        let policy = policy(0.2, None);
+        for attempt in 1..=6 {
+            let base = policy.base_delay(attempt).as_secs_f64();
+            for _ in 0..100 {
+                let delay = policy.delay(attempt).as_secs_f64();
+                assert!((base * 0.8 - 1e-9..=base * 1.2 + 1e-9).contains(&delay), "{delay} is not within 20% of {base}");
+            }
+        }
+        let wild = ReconnectPolicy { jitter: 5.0, ..policy };
+        for _ in 0..100 {
+            assert!(wild.delay(1) <= Duration::from_millis(200));
+        }
+    }
+
+    #[test]
+    fn gives_up_after_the_last_attempt() {
+        let policy = policy(0.0, Some(3));
+        assert!(!policy.exhausted(3));
+        assert!(policy.exhausted(4));
+        assert!(!self::policy(0.0, None).exhausted(u32::MAX));
+    }
+}
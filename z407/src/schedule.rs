@@ -0,0 +1,281 @@
+//! Time-based rules, stored in their own TOML file so the GUI can rewrite it without touching
+//! the hand-edited config:
+//!
+//! ```toml
+//! [[rule]]
+//! name = "morning"
+//! cron = "0 7 * * *"          # 5 fields, or 6 with seconds first
+//! command = "switch_bluetooth"
+//!
+//! [[rule]]
+//! name = "quiet hours"
+//! cron = "0 22 * * *"
+//! preset = "quiet"
+//! speaker = "lounge"
+//! if_disconnected = "skip"
+//! ```
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Local};
+use serde::{de, Deserialize, Deserializer, Serialize};
+
+use crate::config::default_config_path;
+use crate::protocol::Command;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IfDisconnected {
+    /// Run once the speaker reconnects. Only the latest firing of each rule is kept.
+    #[default]
+    Queue,
+    Skip,
+}
+
+impl fmt::Display for IfDisconnected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IfDisconnected::Queue => f.pad("queue"),
+            IfDisconnected::Skip => f.pad("skip"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: String,
+    pub cron: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<Command>,
+    /// Times to send `command`, default 1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub steps: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+    /// Speaker or group; every speaker when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speaker: Option<String>,
+    #[serde(default)]
+    pub if_disconnected: IfDisconnected,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+/// What a rule does, for display and for parsing the GUI's one-line form.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuleAction {
+    Command { command: Command, steps: u32 },
+    Preset(String),
+}
+
+impl fmt::Display for RuleAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuleAction::Command { command, steps: 1 } => write!(f, "{}", command_name(*command)),
+            RuleAction::Command { command, steps } => write!(f, "{} x{}", command_name(*command), steps),
+            RuleAction::Preset(name) => write!(f, "preset {}", name),
+        }
+    }
+}
+
+fn command_name(command: Command) -> String {
+    serde_json::to_value(command).ok().and_then(|v| v.as_str().map(str::to_string)).unwrap_or_default()
+}
+
+/// Accepts what `Display` prints: `volume_down`, `volume_down x5` or `preset quiet`.
+impl FromStr for RuleAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if let Some(name) = s.strip_prefix("preset ") {
+            return Ok(RuleAction::Preset(name.trim().to_string()));
+        }
+        let (name, steps) = match s.split_once(" x") {
+            Some((name, steps)) => (name, steps.trim().parse().map_err(|_| anyhow!("Invalid step count in {:?}", s))?),
+            None => (s, 1),
+        };
+        let command = serde_json::from_value(serde_json::Value::String(name.trim().to_string()))
+            .map_err(|_| anyhow!("Unknown command {:?}, expected e.g. \"switch_aux\" or \"preset quiet\"", name))?;
+        Ok(RuleAction::Command { command, steps })
+    }
+}
+
+impl<'de> Deserialize<'de> for RuleAction {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
+
+impl Rule {
+    pub fn action(&self) -> Result<RuleAction> {
+        match (&self.command, &self.preset) {
+            (Some(command), None) => Ok(RuleAction::Command { command: *command, steps: self.steps.unwrap_or(1) }),
+            (None, Some(preset)) => Ok(RuleAction::Preset(preset.clone())),
+            _ => Err(anyhow!("Rule {:?} needs exactly one of `command` and `preset`", self.name)),
+        }
+    }
+
+    pub fn set_action(&mut self, action: RuleAction) {
+        (self.command, self.steps, self.preset) = match action {
+            RuleAction::Command { command, steps } => (Some(command), (steps != 1).then_some(steps), None),
+            RuleAction::Preset(name) => (None, None, Some(name)),
+        };
+    }
+
+    pub fn schedule(&self) -> Result<cron::Schedule> {
+        parse_cron(&self.cron).with_context(|| format!("Rule {:?}", self.name))
+    }
+
+    /// The first firing after `now`, if the expression has one.
+    pub fn next_after(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        self.schedule().ok()?.after(&now).next()
+    }
+}
+
+/// Standard 5-field cron (`min hour day month weekday`, 0 or 7 being Sunday), or the `cron`
+/// crate's 6/7-field form with seconds first, where weekdays are numbered from 1 for Sunday.
+pub fn parse_cron(expr: &str) -> Result<cron::Schedule> {
+    let fields: Vec<&str> = expr.split_whitespace().collect();
+    let converted = match fields.as_slice() {
+        [min, hour, day, month, weekday] => {
+            let weekday = crate_weekdays(weekday).map_err(|e| anyhow!("invalid cron expression {:?}: {}", expr, e))?;
+            format!("0 {} {} {} {} {}", min, hour, day, month, weekday)
+        }
+        _ => fields.join(" "),
+    };
+    cron::Schedule::from_str(&converted).map_err(|e| anyhow!("invalid cron expression {:?}: {}", expr, e))
+}
+
+/// Rewrites a standard weekday field's numbers into the `cron` crate's. Names, `*` and `?`
+/// mean the same to both and are kept.
+fn crate_weekdays(field: &str) -> Result<String> {
+    let items = field.split(',').map(|item| {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, Some(step)),
+            None => (item, None),
+        };
+        let (from, to) = range.split_once('-').unwrap_or((range, range));
+        match (from.parse::<u8>(), to.parse::<u8>()) {
+            (Ok(from), Ok(to)) => {
+                // `n/step` runs from n to Saturday.
+                let to = if step.is_some() && !range.contains('-') { 6 } else { to };
+                let step = match step.map(str::parse::<usize>) {
+                    None => 1,
+                    Some(Ok(step)) if step > 0 => step,
+                    _ => return Err(anyhow!("bad step in weekday {:?}", item)),
+                };
+                if from > to || to > 7 {
+                    return Err(anyhow!("weekday {:?} is out of 0-7", item));
+                }
+                let mut days: Vec<u8> = (from..=to).step_by(step).map(|day| day % 7 + 1).collect();
+                days.sort();
+                days.dedup();
+                Ok(days.iter().map(u8::to_string).collect::<Vec<_>>().join(","))
+            }
+            (Err(_), Err(_)) => Ok(item.to_string()),
+            _ => Err(anyhow!("weekday {:?} mixes a number and a name", item)),
+        }
+    });
+    Ok(items.collect::<Result<Vec<_>>>()?.join(","))
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Schedule {
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<Rule>,
+}
+
+/// `schedule.toml` next to the default config file.
+pub fn default_schedule_path() -> Option<PathBuf> {
+    default_config_path().map(|path| path.with_file_name("schedule.toml"))
+}
+
+impl Schedule {
+    /// A missing file is an empty schedule.
+    pub fn load(path: &Path) -> Result<Schedule> {
+        if !path.exists() {
+            return Ok(Schedule::default());
+        }
+        let text = fs::read_to_string(path).with_context(|| format!("Cannot read {}", path.display()))?;
+        Schedule::parse(&text).with_context(|| format!("Invalid schedule {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Schedule> {
+        let schedule: Schedule = toml::from_str(text)?;
+        schedule.validate()?;
+        Ok(schedule)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let mut names: Vec<&str> = Vec::new();
+        for rule in &self.rules {
+            if rule.name.trim().is_empty() {
+                return Err(anyhow!("Every rule needs a `name`"));
+            }
+            if names.contains(&rule.name.as_str()) {
+                return Err(anyhow!("Rule {:?} is defined twice", rule.name));
+            }
+            names.push(&rule.name);
+            rule.schedule()?;
+            rule.action()?;
+        }
+        Ok(())
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        self.validate()?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, toml::to_string(self)?).with_context(|| format!("Cannot write {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Datelike, TimeZone, Weekday};
+
+    use super::*;
+
+    fn weekdays(expr: &str) -> Vec<Weekday> {
+        // The days, from Sunday on, of a week of daily firings.
+        let start = Local.with_ymd_and_hms(2026, 10, 18, 0, 0, 0).unwrap();
+        let end = start + chrono::Duration::days(7);
+        parse_cron(expr).unwrap().after(&start).take_while(|t| *t < end).map(|t| t.weekday()).collect()
+    }
+
+    #[test]
+    fn numbers_weekdays_from_sunday_as_zero() {
+        use Weekday::*;
+        assert_eq!(weekdays("0 7 * * 1-5"), [Mon, Tue, Wed, Thu, Fri]);
+        assert_eq!(weekdays("0 7 * * 0"), [Sun]);
+        assert_eq!(weekdays("0 7 * * 7"), [Sun]);
+        assert_eq!(weekdays("0 7 * * 5-7"), [Sun, Fri, Sat]);
+        assert_eq!(weekdays("0 7 * * 1,3/2"), [Mon, Wed, Fri]);
+        assert_eq!(weekdays("0 7 * * mon-fri"), weekdays("0 7 * * 1-5"));
+        assert_eq!(weekdays("0 7 * * *").len(), 7);
+    }
+
+    #[test]
+    fn keeps_the_crate_numbering_with_seconds() {
+        assert_eq!(weekdays("0 0 7 * * 2"), [Weekday::Mon]);
+    }
+
+    #[test]
+    fn rejects_bad_weekdays() {
+        for expr in ["0 7 * * 8", "0 7 * * 5-1", "0 7 * * 1-fri", "0 7 * * 1/0"] {
+            assert!(parse_cron(expr).is_err(), "{}", expr);
+        }
+    }
+}
@@ -0,0 +1,336 @@
+//! Fires `Schedule` rules at their cron times. The clock is a trait so the timing can be driven
+//! by a `MockClock` instead of the wall clock.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration as StdDuration, SystemTime};
+
+use anyhow::Result;
+use chrono::{DateTime, Duration, Local};
+use tokio::time::interval;
+
+use crate::controller::Z407Controller;
+use crate::schedule::{IfDisconnected, Rule, RuleAction, Schedule};
+use crate::speakers::Fleet;
+
+pub trait Clock: Send + Sync + 'static {
+    fn now(&self) -> DateTime<Local>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+}
+
+/// A clock that only moves when told to.
+#[derive(Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<DateTime<Local>>>,
+}
+
+impl MockClock {
+    pub fn new(start: DateTime<Local>) -> Self {
+        Self { now: Arc::new(Mutex::new(start)) }
+    }
+
+    pub fn set(&self, now: DateTime<Local>) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> DateTime<Local> {
+        *self.now.lock().unwrap()
+    }
+}
+
+/// One rule to run on one speaker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Job {
+    pub rule: String,
+    pub speaker: String,
+    pub action: RuleAction,
+}
+
+/// Tracks when each rule fires next and which firings wait for a speaker to reconnect.
+pub struct Scheduler<C: Clock> {
+    clock: C,
+    /// Keyed by rule name and cron expression, so an edited rule starts afresh.
+    next: HashMap<(String, String), DateTime<Local>>,
+    queued: Vec<Job>,
+}
+
+impl<C: Clock> Scheduler<C> {
+    pub fn new(clock: C) -> Self {
+        Self { clock, next: HashMap::new(), queued: Vec::new() }
+    }
+
+    /// Jobs due now: rules whose time has come since the last call, once each even if several
+    /// firings were missed, plus queued jobs whose speaker is back. A rule first seen here is
+    /// only due at its next time from now.
+    pub fn tick(&mut self, schedule: &Schedule, fleet: &Fleet) -> Vec<Job> {
+        let now = self.clock.now();
+        let rules: Vec<&Rule> = schedule.rules.iter().filter(|r| r.enabled).collect();
+        self.next.retain(|(name, cron), _| rules.iter().any(|r| &r.name == name && &r.cron == cron));
+        self.queued.retain(|job| rules.iter().any(|r| r.name == job.rule));
+
+        let mut jobs = Vec::new();
+        for rule in rules {
+            let (Ok(cron), Ok(action)) = (rule.schedule(), rule.action()) else { continue };
+            let key = (rule.name.clone(), rule.cron.clone());
+            let Some(due) = self.next.get(&key).copied().or_else(|| cron.after(&now).next()) else { continue };
+            if due > now {
+                self.next.insert(key, due);
+                continue;
+            }
+            match cron.after(&now).next() {
+                Some(next) => self.next.insert(key, next),
+                None => self.next.remove(&key),
+            };
+
+            let targets = match fleet.resolve(rule.speaker.as_deref()) {
+                Ok(targets) => targets,
+                Err(e) => {
+                    eprintln!("Rule {:?}: {}", rule.name, e);
+                    continue;
+                }
+            };
+            for (speaker, ctl) in targets {
+                let job = Job { rule: rule.name.clone(), speaker: speaker.to_string(), action: action.clone() };
+                if ctl.state.lock().unwrap().connected {
+                    jobs.push(job);
+                } else if rule.if_disconnected == IfDisconnected::Queue {
+                    println!("Rule {:?}: {} is disconnected, queueing", rule.name, speaker);
+                    self.queued.retain(|q| (&q.rule, &q.speaker) != (&job.rule, &job.speaker));
+                    self.queued.push(job);
+                } else {
+                    println!("Rule {:?}: {} is disconnected, skipping", rule.name, speaker);
+                }
+            }
+        }
+
+        let connected = |job: &Job| fleet.get(&job.speaker).is_some_and(|ctl| ctl.state.lock().unwrap().connected);
+        let (ready, waiting) = self.queued.drain(..).partition(connected);
+        self.queued = waiting;
+        jobs.extend(ready);
+        jobs
+    }
+
+    /// Jobs waiting for their speaker to reconnect.
+    pub fn queued(&self) -> &[Job] {
+        &self.queued
+    }
+
+    /// When each enabled rule fires next, as far as `tick` has looked.
+    pub fn next_times(&self) -> Vec<(String, DateTime<Local>)> {
+        let mut times: Vec<_> = self.next.iter().map(|((name, _), at)| (name.clone(), *at)).collect();
+        times.sort_by_key(|(_, at)| *at);
+        times
+    }
+}
+
+/// Sends the command `steps` times, or applies the named preset from `fleet`.
+pub async fn run_action(fleet: &Fleet, ctl: &Z407Controller, action: &RuleAction) -> Result<()> {
+    match action {
+        RuleAction::Command { command, steps } => {
+            for _ in 0..*steps {
+                ctl.execute(*command).await?;
+            }
+        }
+        RuleAction::Preset(name) => ctl.apply_preset(name, fleet.preset(name)?).await?,
+    }
+    Ok(())
+}
+
+/// Runs the schedule in `path` until the task is dropped, re-reading the file whenever it
+/// changes so edits from the GUI take effect without a restart.
+pub async fn run<C: Clock>(fleet: Fleet, path: PathBuf, clock: C) {
+    let mut scheduler = Scheduler::new(clock);
+    let mut schedule = Schedule::default();
+    let mut loaded: Option<Option<SystemTime>> = None;
+    let mut tick = interval(StdDuration::from_secs(1));
+    println!("Scheduler using {}", path.display());
+
+    loop {
+        tick.tick().await;
+        let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+        if loaded != Some(modified) {
+            loaded = Some(modified);
+            match Schedule::load(&path) {
+                Ok(new) => schedule = new,
+                Err(e) => eprintln!("{:#}", e),
+            }
+        }
+
+        for job in scheduler.tick(&schedule, &fleet) {
+            let Some(ctl) = fleet.get(&job.speaker).cloned() else { continue };
+            let fleet = fleet.clone();
+            tokio::spawn(async move {
+                println!("Rule {:?}: {} on {}", job.rule, job.action, job.speaker);
+                if let Err(e) = run_action(&fleet, &ctl, &job.action).await {
+                    eprintln!("Rule {:?} failed on {}: {}", job.rule, job.speaker, e);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::protocol::Command;
+
+    fn at(hour: u32, min: u32, sec: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(2026, 10, 17, hour, min, sec).unwrap()
+    }
+
+    fn rule(name: &str, cron: &str, if_disconnected: IfDisconnected) -> Rule {
+        Rule {
+            name: name.to_string(),
+            cron: cron.to_string(),
+            command: Some(Command::VolumeDown),
+            steps: None,
+            preset: None,
+            speaker: None,
+            if_disconnected,
+            enabled: true,
+        }
+    }
+
+    fn schedule(rules: &[Rule]) -> Schedule {
+        Schedule { rules: rules.to_vec() }
+    }
+
+    fn connect(fleet: &Fleet, name: &str, connected: bool) {
+        fleet.get(name).unwrap().state.lock().unwrap().connected = connected;
+    }
+
+    fn fired(jobs: &[Job]) -> Vec<(&str, &str)> {
+        jobs.iter().map(|job| (job.rule.as_str(), job.speaker.as_str())).collect()
+    }
+
+    /// A scheduler that has seen `rules` once, at 21:59:30.
+    fn started(rules: &Schedule, fleet: &Fleet) -> (Scheduler<MockClock>, MockClock) {
+        let clock = MockClock::new(at(21, 59, 30));
+        let mut scheduler = Scheduler::new(clock.clone());
+        assert!(scheduler.tick(rules, fleet).is_empty());
+        (scheduler, clock)
+    }
+
+    #[test]
+    fn fires_a_rule_once_when_due() {
+        let fleet = Fleet::detached(&["lounge"], &[]);
+        connect(&fleet, "lounge", true);
+        let rules = schedule(&[rule("night", "0 22 * * *", IfDisconnected::Queue)]);
+        let (mut scheduler, clock) = started(&rules, &fleet);
+        assert_eq!(scheduler.next_times(), [("night".to_string(), at(22, 0, 0))]);
+
+        clock.set(at(21, 59, 59));
+        assert!(scheduler.tick(&rules, &fleet).is_empty());
+        clock.set(at(22, 0, 0));
+        let jobs = scheduler.tick(&rules, &fleet);
+        assert_eq!(fired(&jobs), [("night", "lounge")]);
+        assert_eq!(jobs[0].action, RuleAction::Command { command: Command::VolumeDown, steps: 1 });
+        assert!(scheduler.tick(&rules, &fleet).is_empty());
+        assert_eq!(scheduler.next_times()[0].1, at(22, 0, 0) + Duration::days(1));
+    }
+
+    #[test]
+    fn collapses_missed_firings_into_one() {
+        let fleet = Fleet::detached(&["lounge"], &[]);
+        connect(&fleet, "lounge", true);
+        let rules = schedule(&[rule("every minute", "* * * * *", IfDisconnected::Queue)]);
+        let (mut scheduler, clock) = started(&rules, &fleet);
+        clock.advance(Duration::minutes(10));
+        assert_eq!(fired(&scheduler.tick(&rules, &fleet)), [("every minute", "lounge")]);
+        assert!(scheduler.tick(&rules, &fleet).is_empty());
+        assert_eq!(scheduler.next_times()[0].1, at(22, 10, 0));
+    }
+
+    #[test]
+    fn sends_each_targeted_speaker_a_job() {
+        let fleet = Fleet::detached(&["lounge", "kitchen", "office"], &[("downstairs", &["lounge", "kitchen"])]);
+        ["lounge", "kitchen", "office"].iter().for_each(|name| connect(&fleet, name, true));
+        let mut night = rule("night", "0 22 * * *", IfDisconnected::Queue);
+        night.speaker = Some("downstairs".to_string());
+        let rules = schedule(&[night]);
+        let (mut scheduler, clock) = started(&rules, &fleet);
+        clock.set(at(22, 0, 0));
+        assert_eq!(fired(&scheduler.tick(&rules, &fleet)), [("night", "lounge"), ("night", "kitchen")]);
+    }
+
+    #[test]
+    fn queues_or_skips_for_a_disconnected_speaker() {
+        let fleet = Fleet::detached(&["lounge"], &[]);
+        let rules = schedule(&[
+            rule("queued", "0 22 * * *", IfDisconnected::Queue),
+            rule("skipped", "0 22 * * *", IfDisconnected::Skip),
+        ]);
+        let (mut scheduler, clock) = started(&rules, &fleet);
+        clock.set(at(22, 0, 0));
+        assert!(scheduler.tick(&rules, &fleet).is_empty());
+        assert_eq!(fired(scheduler.queued()), [("queued", "lounge")]);
+
+        // Another firing while still away replaces the queued one.
+        clock.set(at(22, 0, 0) + Duration::days(1));
+        assert!(scheduler.tick(&rules, &fleet).is_empty());
+        assert_eq!(scheduler.queued().len(), 1);
+
+        clock.advance(Duration::seconds(5));
+        connect(&fleet, "lounge", true);
+        assert_eq!(fired(&scheduler.tick(&rules, &fleet)), [("queued", "lounge")]);
+        assert!(scheduler.queued().is_empty());
+        assert!(scheduler.tick(&rules, &fleet).is_empty());
+    }
+
+    #[test]
+    fn drops_a_queued_job_when_its_rule_is_disabled() {
+        let fleet = Fleet::detached(&["lounge"], &[]);
+        let mut queued = rule("queued", "0 22 * * *", IfDisconnected::Queue);
+        let (mut scheduler, clock) = started(&schedule(&[queued.clone()]), &fleet);
+        clock.set(at(22, 0, 0));
+        scheduler.tick(&schedule(&[queued.clone()]), &fleet);
+        assert_eq!(scheduler.queued().len(), 1);
+
+        queued.enabled = false;
+        let rules = schedule(&[queued]);
+        assert!(scheduler.tick(&rules, &fleet).is_empty());
+        assert!(scheduler.queued().is_empty());
+        connect(&fleet, "lounge", true);
+        assert!(scheduler.tick(&rules, &fleet).is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_schedules() {
+        let errors = [
+            ("[[rule]]\nname = \"a\"\ncron = \"61 * * * *\"\ncommand = \"play_pause\"", "invalid cron expression"),
+            ("[[rule]]\nname = \"a\"\ncron = \"0 22 * * *\"", "needs exactly one of `command` and `preset`"),
+            ("[[rule]]\nname = \"a\"\ncron = \"0 22 * * *\"\ncommand = \"play_pause\"\npreset = \"quiet\"", "needs exactly one"),
+            ("[[rule]]\nname = \"a\"\ncron = \"0 22 * * *\"\ncommand = \"warp\"", "unknown variant `warp`"),
+            ("[[rule]]\nname = \"a\"\ncron = \"0 22 * * *\"\ncommand = \"play_pause\"\nwhen = 1", "unknown field `when`"),
+            ("[[rule]]\nname = \" \"\ncron = \"0 22 * * *\"\ncommand = \"play_pause\"", "needs a `name`"),
+            ("[[rule]]\nname = \"a\"\ncron = \"0 22 * * *\"\ncommand = \"play_pause\"\n[[rule]]\nname = \"a\"\ncron = \"0 7 * * *\"\ncommand = \"play_pause\"", "defined twice"),
+        ];
+        for (text, expected) in errors {
+            let error = format!("{:#}", Schedule::parse(text).unwrap_err());
+            assert!(error.contains(expected), "{:?} gave {:?}", text, error);
+        }
+        let text = "[[rule]]\nname = \"a\"\ncron = \"0 22 * * *\"\npreset = \"quiet\"\nif_disconnected = \"skip\"";
+        let rule = &Schedule::parse(text).unwrap().rules[0];
+        assert_eq!(rule.if_disconnected, IfDisconnected::Skip);
+        assert_eq!(Schedule::load(Path::new("/nonexistent/schedule.toml")).unwrap(), Schedule::default());
+    }
+}
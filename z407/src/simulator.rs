@@ -0,0 +1,403 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time::Instant;
+
+use crate::protocol::{Command, Response};
+use crate::state::{Input, BASS_STEPS, VOLUME_STEPS};
+use crate::transport::{DeviceFilter, DeviceInfo, Z407Transport};
+
+/// The real step counts are not documented; these defaults only need to be plausible.
+#[derive(Debug, Clone)]
+pub struct SimulatorConfig {
+    /// Advertised id and name, so several simulated speakers can be told apart.
+    pub id: String,
+    pub name: String,
+    pub volume_steps: u8,
+    pub bass_steps: u8,
+    pub initial_volume: u8,
+    pub initial_bass: u8,
+    pub initial_input: Input,
+    pub bass_mode_timeout: Duration,
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> Self {
+        Self {
+            id: "simulator".to_string(),
+            name: "Z407 (simulated)".to_string(),
+            volume_steps: VOLUME_STEPS,
+            bass_steps: BASS_STEPS,
+            initial_volume: 25,
+            initial_bass: 10,
+            initial_input: Input::Bluetooth,
+            bass_mode_timeout: Duration::from_secs(15),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HandshakeStage {
+    Idle,
+    Initiated,
+    Done,
+}
+
+struct SimState {
+    config: SimulatorConfig,
+    advertising: bool,
+    connected: bool,
+    handshake: HandshakeStage,
+    volume: u8,
+    bass: u8,
+    input: Input,
+    bass_mode_until: Option<Instant>,
+    notify_tx: Option<UnboundedSender<Vec<u8>>>,
+}
+
+impl SimState {
+    fn emit(&self, resp: Response) {
+        if let Some(tx) = &self.notify_tx {
+            let _ = tx.send(resp.encode());
+        }
+    }
+
+    fn in_bass_mode(&self) -> bool {
+        self.bass_mode_until.is_some_and(|until| Instant::now() < until)
+    }
+
+    fn step_volume(&mut self, up: bool) {
+        self.volume = if up {
+            (self.volume + 1).min(self.config.volume_steps)
+        } else {
+            self.volume.saturating_sub(1)
+        };
+        self.emit(if up { Response::VolumeUp } else { Response::VolumeDown });
+    }
+
+    fn step_bass(&mut self, up: bool) {
+        self.bass = if up {
+            (self.bass + 1).min(self.config.bass_steps)
+        } else {
+            self.bass.saturating_sub(1)
+        };
+        self.emit(if up { Response::BassUp } else { Response::BassDown });
+    }
+
+    fn switch_input(&mut self, input: Input) {
+        self.emit(match input {
+            Input::Bluetooth => Response::SwitchBluetooth,
+            Input::Aux => Response::SwitchAux,
+            Input::Usb => Response::SwitchUsb,
+        });
+        if self.input != input {
+            self.input = input;
+            self.emit(match input {
+                Input::Bluetooth => Response::SwitchedBluetooth,
+                Input::Aux => Response::SwitchedAux,
+                Input::Usb => Response::SwitchedUsb,
+            });
+        }
+    }
+
+    fn handle(&mut self, cmd: Command) {
+        match cmd {
+            Command::Initiate => {
+                self.handshake = HandshakeStage::Initiated;
+                self.emit(Response::Initiated);
+                return;
+            }
+            Command::Acknowledge if self.handshake == HandshakeStage::Initiated => {
+                self.handshake = HandshakeStage::Done;
+                self.emit(Response::Acknowledged);
+                self.emit(Response::Connected);
+                return;
+            }
+            _ if self.handshake != HandshakeStage::Done => return,
+            _ => {}
+        }
+
+        match cmd {
+            Command::Initiate | Command::Acknowledge => {}
+            Command::VolumeUp => self.step_volume(true),
+            Command::VolumeDown => self.step_volume(false),
+            Command::BassUp => self.step_bass(true),
+            Command::BassDown => self.step_bass(false),
+            Command::PlayPause => self.emit(Response::PlayPause),
+            Command::NextTrack => self.emit(Response::NextTrack),
+            Command::PrevTrack => self.emit(Response::PrevTrack),
+            Command::SwitchBluetooth => self.switch_input(Input::Bluetooth),
+            Command::SwitchAux => self.switch_input(Input::Aux),
+            Command::SwitchUsb => self.switch_input(Input::Usb),
+            Command::Sound1 => self.emit(Response::Sound1),
+            Command::Sound2 => self.emit(Response::Sound2),
+            Command::Sound3 => self.emit(Response::Sound3),
+            Command::Unknown1 => self.emit(Response::Unknown1),
+            Command::Pairing => self.emit(Response::Pairing),
+            Command::FactoryReset => {
+                self.volume = self.config.initial_volume;
+                self.bass = self.config.initial_bass;
+                self.input = self.config.initial_input;
+                self.bass_mode_until = None;
+                self.emit(Response::FactoryReset);
+            }
+        }
+    }
+}
+
+/// A software Z407 that speaks the protocol from the README. Use the paired [`SimulatorHandle`]
+/// to inspect its levels, operate the physical puck or drop the link.
+pub struct Simulator {
+    state: Arc<Mutex<SimState>>,
+}
+
+#[derive(Clone)]
+pub struct SimulatorHandle {
+    state: Arc<Mutex<SimState>>,
+}
+
+impl Simulator {
+    pub fn new(config: SimulatorConfig) -> (Simulator, SimulatorHandle) {
+        let state = Arc::new(Mutex::new(SimState {
+            advertising: true,
+            connected: false,
+            handshake: HandshakeStage::Idle,
+            volume: config.initial_volume,
+            bass: config.initial_bass,
+            input: config.initial_input,
+            bass_mode_until: None,
+            notify_tx: None,
+            config,
+        }));
+        (Simulator { state: state.clone() }, SimulatorHandle { state })
+    }
+}
+
+impl SimulatorHandle {
+    pub fn volume(&self) -> u8 {
+        self.state.lock().unwrap().volume
+    }
+
+    pub fn bass(&self) -> u8 {
+        self.state.lock().unwrap().bass
+    }
+
+    pub fn input(&self) -> Input {
+        self.state.lock().unwrap().input
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state.lock().unwrap().connected
+    }
+
+    pub fn in_bass_mode(&self) -> bool {
+        self.state.lock().unwrap().in_bass_mode()
+    }
+
+    pub fn set_advertising(&self, advertising: bool) {
+        self.state.lock().unwrap().advertising = advertising;
+    }
+
+    pub fn drop_link(&self) {
+        let mut s = self.state.lock().unwrap();
+        s.connected = false;
+        s.handshake = HandshakeStage::Idle;
+        s.notify_tx = None;
+    }
+
+    /// Twists the puck: adjusts bass while in bass mode, volume otherwise.
+    pub fn twist(&self, up: bool) {
+        let mut s = self.state.lock().unwrap();
+        if s.in_bass_mode() {
+            s.bass_mode_until = Some(Instant::now() + s.config.bass_mode_timeout);
+            s.step_bass(up);
+        } else {
+            s.step_volume(up);
+        }
+    }
+
+    pub fn press(&self) {
+        self.state.lock().unwrap().emit(Response::PlayPause);
+    }
+
+    pub fn double_press(&self) {
+        self.state.lock().unwrap().emit(Response::NextTrack);
+    }
+
+    pub fn triple_press(&self) {
+        self.state.lock().unwrap().emit(Response::PrevTrack);
+    }
+
+    /// Enters bass mode, which exits on its own after `bass_mode_timeout` without a twist.
+    pub fn long_press(&self) {
+        let mut s = self.state.lock().unwrap();
+        s.bass_mode_until = Some(Instant::now() + s.config.bass_mode_timeout);
+        s.emit(Response::Sound2);
+    }
+}
+
+impl Z407Transport for Simulator {
+    async fn scan(&mut self, timeout: Duration, filter: &DeviceFilter) -> Result<Option<DeviceInfo>> {
+        let found = self.discover(timeout).await?;
+        Ok(found.into_iter().find(|d| filter.matches(d)))
+    }
+
+    async fn discover(&mut self, _timeout: Duration) -> Result<Vec<DeviceInfo>> {
+        let s = self.state.lock().unwrap();
+        if !s.advertising {
+            return Ok(Vec::new());
+        }
+        Ok(vec![DeviceInfo {
+            id: s.config.id.clone(),
+            name: Some(s.config.name.clone()),
+            rssi: Some(-40),
+        }])
+    }
+
+    async fn connect(&mut self, _device: &DeviceInfo) -> Result<()> {
+        let mut s = self.state.lock().unwrap();
+        if !s.advertising {
+            bail!("Simulated speaker is not advertising");
+        }
+        s.connected = true;
+        s.handshake = HandshakeStage::Idle;
+        Ok(())
+    }
+
+    async fn subscribe(&mut self) -> Result<UnboundedReceiver<Vec<u8>>> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.state.lock().unwrap().notify_tx = Some(tx);
+        Ok(rx)
+    }
+
+    async fn write(&mut self, cmd: Command) -> Result<()> {
+        let mut s = self.state.lock().unwrap();
+        if !s.connected {
+            bail!("Not connected");
+        }
+        s.handle(cmd);
+        Ok(())
+    }
+
+    async fn disconnect(&mut self) -> Result<()> {
+        let mut s = self.state.lock().unwrap();
+        s.connected = false;
+        s.handshake = HandshakeStage::Idle;
+        s.notify_tx = None;
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        self.state.lock().unwrap().connected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A simulator that has been connected to, but not handshaken with.
+    async fn connected(config: SimulatorConfig) -> (Simulator, SimulatorHandle, UnboundedReceiver<Vec<u8>>) {
+        let (mut simulator, handle) = Simulator::new(config);
+        let device = simulator.scan(Duration::from_secs(1), &DeviceFilter::default()).await.unwrap().unwrap();
+        simulator.connect(&device).await.unwrap();
+        let notifications = simulator.subscribe().await.unwrap();
+        (simulator, handle, notifications)
+    }
+
+    async fn handshaken(config: SimulatorConfig) -> (Simulator, SimulatorHandle, UnboundedReceiver<Vec<u8>>) {
+        let (mut simulator, handle, mut notifications) = connected(config).await;
+        simulator.write(Command::Initiate).await.unwrap();
+        simulator.write(Command::Acknowledge).await.unwrap();
+        received(&mut notifications);
+        (simulator, handle, notifications)
+    }
+
+    fn received(notifications: &mut UnboundedReceiver<Vec<u8>>) -> Vec<Response> {
+        std::iter::from_fn(|| notifications.try_recv().ok()).map(|bytes| Response::decode(&bytes)).collect()
+    }
+
+    #[tokio::test]
+    async fn answers_only_after_the_handshake() {
+        let (mut simulator, puck, mut notifications) = connected(SimulatorConfig::default()).await;
+        simulator.write(Command::VolumeUp).await.unwrap();
+        simulator.write(Command::Acknowledge).await.unwrap();
+        assert_eq!(received(&mut notifications), []);
+        assert_eq!(puck.volume(), 25);
+
+        simulator.write(Command::Initiate).await.unwrap();
+        assert_eq!(received(&mut notifications), [Response::Initiated]);
+        simulator.write(Command::Acknowledge).await.unwrap();
+        assert_eq!(received(&mut notifications), [Response::Acknowledged, Response::Connected]);
+        simulator.write(Command::VolumeUp).await.unwrap();
+        assert_eq!(received(&mut notifications), [Response::VolumeUp]);
+        assert_eq!(puck.volume(), 26);
+    }
+
+    #[tokio::test]
+    async fn reports_an_input_switch_only_on_change() {
+        let (mut simulator, puck, mut notifications) = handshaken(SimulatorConfig::default()).await;
+        let mut switch = async |command| {
+            simulator.write(command).await.unwrap();
+            received(&mut notifications)
+        };
+        assert_eq!(switch(Command::SwitchBluetooth).await, [Response::SwitchBluetooth]);
+        assert_eq!(switch(Command::SwitchAux).await, [Response::SwitchAux, Response::SwitchedAux]);
+        assert_eq!(switch(Command::SwitchUsb).await, [Response::SwitchUsb, Response::SwitchedUsb]);
+        assert_eq!(switch(Command::SwitchUsb).await, [Response::SwitchUsb]);
+        assert_eq!(switch(Command::SwitchBluetooth).await, [Response::SwitchBluetooth, Response::SwitchedBluetooth]);
+        assert_eq!(puck.input(), Input::Bluetooth);
+    }
+
+    #[tokio::test]
+    async fn confirms_steps_past_either_end() {
+        let config = SimulatorConfig { volume_steps: 3, bass_steps: 2, initial_volume: 1, initial_bass: 1, ..Default::default() };
+        let (mut simulator, puck, mut notifications) = handshaken(config).await;
+        for _ in 0..3 {
+            simulator.write(Command::VolumeDown).await.unwrap();
+            simulator.write(Command::BassDown).await.unwrap();
+        }
+        assert_eq!((puck.volume(), puck.bass()), (0, 0));
+        assert_eq!(received(&mut notifications).len(), 6);
+        for _ in 0..5 {
+            simulator.write(Command::VolumeUp).await.unwrap();
+            simulator.write(Command::BassUp).await.unwrap();
+        }
+        assert_eq!((puck.volume(), puck.bass()), (3, 2));
+        assert_eq!(received(&mut notifications).len(), 10);
+    }
+
+    #[tokio::test]
+    async fn leaves_bass_mode_after_its_timeout() {
+        let config = SimulatorConfig { bass_mode_timeout: Duration::from_millis(100), ..Default::default() };
+        let (_simulator, puck, mut notifications) = handshaken(config).await;
+        puck.long_press();
+        assert!(puck.in_bass_mode());
+        puck.twist(true);
+        assert_eq!(received(&mut notifications), [Response::Sound2, Response::BassUp]);
+        assert_eq!((puck.volume(), puck.bass()), (25, 11));
+
+        // Each twist in bass mode restarts the timeout.
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        puck.twist(false);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(puck.in_bass_mode());
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(!puck.in_bass_mode());
+        puck.twist(true);
+        assert_eq!(received(&mut notifications), [Response::BassDown, Response::VolumeUp]);
+        assert_eq!((puck.volume(), puck.bass()), (26, 10));
+    }
+
+    #[tokio::test]
+    async fn refuses_writes_once_the_link_drops() {
+        let (mut simulator, puck, _notifications) = handshaken(SimulatorConfig::default()).await;
+        puck.drop_link();
+        assert!(!simulator.is_connected() && !puck.is_connected());
+        assert!(simulator.write(Command::PlayPause).await.is_err());
+        puck.set_advertising(false);
+        assert_eq!(simulator.scan(Duration::from_secs(1), &DeviceFilter::default()).await.unwrap(), None);
+    }
+}
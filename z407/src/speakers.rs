@@ -0,0 +1,325 @@
+//! Several speakers at once. Each gets its own `Z407Connection`, and with it its own BLE link,
+//! `Z407State` and reconnect loop; commands address them by name, by group, or as `all`.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use tokio::runtime::Handle;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+
+use crate::config::{Config, SpeakerConfig};
+use crate::connection::Z407Connection;
+use crate::controller::Z407Controller;
+use crate::event::Event;
+use crate::preset::{self, Preset};
+use crate::protocol::Response;
+use crate::simulator::{Simulator, SimulatorConfig, SimulatorHandle};
+use crate::state::Z407State;
+#[cfg(feature = "bluest")]
+use crate::transport::BleTransport;
+
+/// Targets every speaker.
+pub const ALL: &str = "all";
+/// Name of the only speaker when the config lists none.
+pub const DEFAULT: &str = "default";
+
+/// Names `target` picks out of `names`, in the order given. `None` means all of them.
+pub fn resolve<'a>(target: Option<&str>, names: &[&'a str], groups: &BTreeMap<String, Vec<String>>) -> Result<Vec<&'a str>> {
+    let target = target.unwrap_or(ALL);
+    if target == ALL {
+        return Ok(names.to_vec());
+    }
+    if let Some(name) = names.iter().find(|n| **n == target) {
+        return Ok(vec![*name]);
+    }
+    match groups.get(target) {
+        Some(members) => Ok(names.iter().filter(|n| members.iter().any(|m| m == *n)).copied().collect()),
+        None => {
+            let known: Vec<&str> = names.iter().copied().chain(groups.keys().map(String::as_str)).collect();
+            Err(anyhow!("Unknown speaker or group {:?} (known: {}, {})", target, known.join(", "), ALL))
+        }
+    }
+}
+
+pub struct Speaker {
+    pub name: String,
+    pub conn: Z407Connection,
+}
+
+/// Owns the connections. Hand `fleet()` to async code that needs to reach them.
+pub struct Speakers {
+    speakers: Vec<Speaker>,
+    groups: BTreeMap<String, Vec<String>>,
+    presets: BTreeMap<String, Preset>,
+}
+
+fn initial_state() -> Z407State {
+    Z407State {
+        scan_requested: true,
+        ..Default::default()
+    }
+}
+
+impl Speakers {
+    /// Connects over BLE to each of `speakers`. A lone unnamed speaker keeps the remembered
+    /// last-used device, as `Z407Connection::spawn_configured` does.
+    #[cfg(feature = "bluest")]
+    pub fn spawn(config: &Config, speakers: &[SpeakerConfig]) -> Self {
+        let speakers = speakers
+            .iter()
+            .map(|speaker| {
+                let conn = if config.speakers.is_empty() {
+                    Z407Connection::spawn_configured(initial_state(), config)
+                } else {
+                    let transport = BleTransport::with_config(config.ble.clone());
+                    Z407Connection::spawn_with(initial_state(), transport, config.speaker_connection_options(speaker))
+                };
+                Speaker { name: speaker.name.clone(), conn }
+            })
+            .collect();
+        Self { speakers, groups: config.groups.clone(), presets: config.presets.clone() }
+    }
+
+    /// One simulator per speaker, advertising the speaker's address and name when it has them.
+    pub fn simulate(config: &Config, speakers: &[SpeakerConfig]) -> Self {
+        Self::simulate_with_pucks(config, speakers).0
+    }
+
+    /// `simulate`, keeping each simulator's handle to work its puck with.
+    pub(crate) fn simulate_with_pucks(config: &Config, speakers: &[SpeakerConfig]) -> (Self, Vec<SimulatorHandle>) {
+        let (speakers, pucks) = speakers
+            .iter()
+            .map(|speaker| {
+                let defaults = SimulatorConfig::default();
+                let (simulator, puck) = Simulator::new(SimulatorConfig {
+                    id: speaker.address.clone().unwrap_or(defaults.id.clone()),
+                    name: speaker.advertised_name.clone().unwrap_or(defaults.name.clone()),
+                    ..defaults
+                });
+                let conn = Z407Connection::spawn_with(initial_state(), simulator, config.speaker_connection_options(speaker));
+                (Speaker { name: speaker.name.clone(), conn }, puck)
+            })
+            .unzip();
+        (Self { speakers, groups: config.groups.clone(), presets: config.presets.clone() }, pucks)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Speaker> {
+        self.speakers.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.speakers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.speakers.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Speaker> {
+        self.speakers.iter().find(|s| s.name == name)
+    }
+
+    /// The first speaker's runtime, for work that isn't tied to one speaker.
+    pub fn runtime(&self) -> &Handle {
+        self.speakers[0].conn.runtime()
+    }
+
+    /// Decoded responses from every speaker since the last call.
+    pub fn responses(&self) -> Vec<(&str, Response)> {
+        self.speakers
+            .iter()
+            .flat_map(|s| s.conn.responses().into_iter().map(|r| (s.name.as_str(), r)))
+            .collect()
+    }
+
+    pub fn fleet(&self) -> Fleet {
+        Fleet {
+            speakers: self.speakers.iter().map(|s| (s.name.clone(), s.conn.controller())).collect(),
+            groups: Arc::new(self.groups.clone()),
+            presets: Arc::new(self.presets.clone()),
+        }
+    }
+}
+
+/// Cheap-to-clone controllers for every speaker, by name.
+#[derive(Clone)]
+pub struct Fleet {
+    speakers: Arc<[(String, Z407Controller)]>,
+    groups: Arc<BTreeMap<String, Vec<String>>>,
+    presets: Arc<BTreeMap<String, Preset>>,
+}
+
+impl Fleet {
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Z407Controller)> {
+        self.speakers.iter().map(|(name, ctl)| (name.as_str(), ctl))
+    }
+
+    pub fn len(&self) -> usize {
+        self.speakers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.speakers.is_empty()
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = &str> {
+        self.groups.keys().map(String::as_str)
+    }
+
+    pub fn presets(&self) -> impl Iterator<Item = &str> {
+        self.presets.keys().map(String::as_str)
+    }
+
+    pub fn preset(&self, name: &str) -> Result<&Preset> {
+        preset::find(&self.presets, name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Z407Controller> {
+        self.iter().find(|(n, _)| *n == name).map(|(_, ctl)| ctl)
+    }
+
+    pub fn resolve(&self, target: Option<&str>) -> Result<Vec<(&str, &Z407Controller)>> {
+        let names: Vec<&str> = self.iter().map(|(name, _)| name).collect();
+        let selected = resolve(target, &names, &self.groups)?;
+        Ok(self.iter().filter(|(name, _)| selected.contains(name)).collect())
+    }
+
+    /// Events from every speaker `target` names. Must be called within a Tokio runtime.
+    pub fn subscribe(&self, target: Option<&str>) -> Result<FleetEvents> {
+        Ok(FleetEvents::subscribe(&self.resolve(target)?))
+    }
+}
+
+#[cfg(test)]
+impl Fleet {
+    /// Speakers with no connection behind them, for tests that set their state by hand.
+    pub(crate) fn detached(names: &[&str], groups: &[(&str, &[&str])]) -> Fleet {
+        let speakers = names.iter().map(|name| {
+            let (cmd_tx, _) = std::sync::mpsc::channel();
+            let (events, _) = tokio::sync::broadcast::channel(16);
+            let state = Arc::new(std::sync::Mutex::new(Z407State::default()));
+            (name.to_string(), Z407Controller::new(state, cmd_tx, events))
+        });
+        let groups = groups.iter().map(|(group, members)| (group.to_string(), members.iter().map(|m| m.to_string()).collect()));
+        Fleet { speakers: speakers.collect(), groups: Arc::new(groups.collect()), presets: Arc::default() }
+    }
+}
+
+/// Events from several speakers merged into one stream, each tagged with its speaker's name.
+pub struct FleetEvents {
+    rx: mpsc::UnboundedReceiver<(String, Event)>,
+    _forwarders: JoinSet<()>,
+}
+
+impl FleetEvents {
+    fn subscribe(targets: &[(&str, &Z407Controller)]) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut forwarders = JoinSet::new();
+        for (name, ctl) in targets {
+            let (name, tx, mut events) = (name.to_string(), tx.clone(), ctl.subscribe());
+            forwarders.spawn(async move {
+                loop {
+                    match events.recv().await {
+                        Ok(event) => {
+                            if tx.send((name.clone(), event)).is_err() {
+                                return;
+                            }
+                        }
+                        Err(RecvError::Lagged(skipped)) => eprintln!("{}: event subscriber lagged, skipped {} events", name, skipped),
+                        Err(RecvError::Closed) => return,
+                    }
+                }
+            });
+        }
+        Self { rx, _forwarders: forwarders }
+    }
+
+    /// `None` once every speaker's connection has shut down.
+    pub async fn recv(&mut self) -> Option<(String, Event)> {
+        self.rx.recv().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::protocol::Command;
+
+    const NAMES: [&str; 3] = ["kitchen", "lounge", "office"];
+
+    fn groups() -> BTreeMap<String, Vec<String>> {
+        BTreeMap::from([("downstairs".to_string(), vec!["lounge".to_string(), "kitchen".to_string()])])
+    }
+
+    #[test]
+    fn resolves_speakers_groups_and_all() {
+        let groups = groups();
+        assert_eq!(resolve(Some("lounge"), &NAMES, &groups).unwrap(), ["lounge"]);
+        // Members come in speaker order, not the group's.
+        assert_eq!(resolve(Some("downstairs"), &NAMES, &groups).unwrap(), ["kitchen", "lounge"]);
+        assert_eq!(resolve(Some(ALL), &NAMES, &groups).unwrap(), NAMES);
+        assert_eq!(resolve(None, &NAMES, &groups).unwrap(), NAMES);
+    }
+
+    #[test]
+    fn lists_the_choices_for_an_unknown_name() {
+        let e = resolve(Some("attic"), &NAMES, &groups()).unwrap_err().to_string();
+        assert_eq!(e, "Unknown speaker or group \"attic\" (known: kitchen, lounge, office, downstairs, all)");
+    }
+
+    #[test]
+    fn resolves_controllers_by_name() {
+        let fleet = Fleet::detached(&NAMES, &[("upstairs", &["office"])]);
+        let names = |target| fleet.resolve(target).unwrap().into_iter().map(|(name, _)| name).collect::<Vec<_>>();
+        assert_eq!(names(Some("upstairs")), ["office"]);
+        assert_eq!(names(Some("kitchen")), ["kitchen"]);
+        assert_eq!(names(None), NAMES);
+        assert!(fleet.resolve(Some("garage")).is_err());
+        assert!(fleet.get("office").is_some() && fleet.get("garage").is_none());
+    }
+
+    #[test]
+    fn merges_the_events_of_a_group() {
+        let config = Config::parse(
+            "[[speaker]]\nname = \"kitchen\"\naddress = \"kitchen\"\n\
+             [[speaker]]\nname = \"lounge\"\naddress = \"lounge\"\n\
+             [[speaker]]\nname = \"office\"\naddress = \"office\"\n\
+             [groups]\ndownstairs = [\"kitchen\", \"lounge\"]\n",
+        )
+        .unwrap();
+        let speakers = Speakers::simulate(&config, &config.speakers());
+        let fleet = speakers.fleet();
+        let runtime = speakers.runtime();
+        runtime.block_on(async {
+            let mut events = fleet.subscribe(Some("downstairs")).unwrap();
+            for name in NAMES {
+                let ctl = fleet.get(name).unwrap();
+                while !ctl.state.lock().unwrap().connected {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+                ctl.execute(Command::PlayPause).await.unwrap();
+            }
+            let mut played = Vec::new();
+            while played.len() < 2 {
+                let next = tokio::time::timeout(Duration::from_secs(5), events.recv()).await;
+                if let Ok(Some((name, Event::Response { response: Response::PlayPause, .. }))) = next {
+                    played.push(name);
+                } else {
+                    assert!(next.is_ok(), "only {:?} reported play/pause", played);
+                }
+            }
+            played.sort();
+            assert_eq!(played, ["kitchen", "lounge"]);
+            // The office played too, but isn't downstairs.
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            while let Ok(Some((name, _))) = tokio::time::timeout(Duration::from_millis(10), events.recv()).await {
+                assert_ne!(name, "office");
+            }
+        });
+    }
+}
@@ -0,0 +1,130 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::ack::CommandResult;
+use crate::level::{Level, LevelKind};
+use crate::preset::PresetProgress;
+use crate::protocol::{Command, Response};
+use crate::reconnect::RetryState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Input {
+    #[serde(alias = "bt")]
+    Bluetooth,
+    Aux,
+    Usb,
+}
+
+impl Input {
+    /// The command that switches to this input.
+    pub fn command(self) -> Command {
+        match self {
+            Input::Bluetooth => Command::SwitchBluetooth,
+            Input::Aux => Command::SwitchAux,
+            Input::Usb => Command::SwitchUsb,
+        }
+    }
+}
+
+impl FromStr for Input {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "bluetooth" | "bt" => Ok(Input::Bluetooth),
+            "aux" => Ok(Input::Aux),
+            "usb" => Ok(Input::Usb),
+            _ => Err(anyhow::anyhow!("Unknown input {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Input::Bluetooth => write!(f, "Bluetooth"),
+            Input::Aux => write!(f, "AUX"),
+            Input::Usb => write!(f, "USB"),
+        }
+    }
+}
+
+/// Assumed number of volume steps between silence and maximum, unless `[levels]` says otherwise.
+pub const VOLUME_STEPS: u8 = 50;
+/// Assumed number of bass steps between minimum and maximum, unless `[levels]` says otherwise.
+pub const BASS_STEPS: u8 = 20;
+
+#[derive(Debug, Clone)]
+pub struct Z407State {
+    pub connected: bool,
+    /// Id of the speaker this state belongs to, once it has connected.
+    pub device: Option<String>,
+    pub volume: Level,
+    pub bass: Level,
+    pub current_input: Option<Input>,
+    pub last_response: Option<Response>,
+    pub last_error: Option<String>,
+    pub retry: Option<RetryState>,
+    pub scan_requested: bool,
+    /// Set while a preset is being applied.
+    pub preset: Option<PresetProgress>,
+}
+
+impl Default for Z407State {
+    fn default() -> Self {
+        Self {
+            connected: false,
+            device: None,
+            volume: Level::new(VOLUME_STEPS),
+            bass: Level::new(BASS_STEPS),
+            current_input: None,
+            last_response: None,
+            last_error: None,
+            retry: None,
+            scan_requested: false,
+            preset: None,
+        }
+    }
+}
+
+impl Z407State {
+    pub fn level(&self, kind: LevelKind) -> Level {
+        match kind {
+            LevelKind::Volume => self.volume,
+            LevelKind::Bass => self.bass,
+        }
+    }
+
+    pub fn level_mut(&mut self, kind: LevelKind) -> &mut Level {
+        match kind {
+            LevelKind::Volume => &mut self.volume,
+            LevelKind::Bass => &mut self.bass,
+        }
+    }
+
+    /// `Level::check_raise` for whichever level `cmd` raises.
+    pub fn check_limits(&self, cmd: Command) -> CommandResult {
+        match LevelKind::raised_by(cmd) {
+            Some(kind) => self.level(kind).check_raise(kind),
+            None => Ok(()),
+        }
+    }
+
+    pub fn apply(&mut self, resp: &Response) {
+        match resp {
+            Response::VolumeUp => self.volume.step(true),
+            Response::VolumeDown => self.volume.step(false),
+            Response::BassUp => self.bass.step(true),
+            Response::BassDown => self.bass.step(false),
+            Response::SwitchBluetooth | Response::SwitchedBluetooth => self.current_input = Some(Input::Bluetooth),
+            Response::SwitchAux | Response::SwitchedAux => self.current_input = Some(Input::Aux),
+            Response::SwitchUsb | Response::SwitchedUsb => self.current_input = Some(Input::Usb),
+            Response::Unknown(bytes) => eprintln!("Unknown response: {}", hex::encode(bytes)),
+            _ => {}
+        }
+        self.last_response = Some(resp.clone());
+    }
+}
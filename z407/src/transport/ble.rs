@@ -0,0 +1,193 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use bluest::{Adapter, AdvertisingDevice, Characteristic, Device, Uuid};
+use futures_util::stream::StreamExt;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::time::{timeout_at, Instant};
+
+use super::{DeviceFilter, DeviceInfo, Z407Transport};
+use crate::config::BleConfig;
+use crate::protocol::Command;
+
+#[derive(Default)]
+pub struct BleTransport {
+    config: BleConfig,
+    adapter: Option<Adapter>,
+    found: Option<Device>,
+    device: Option<Device>,
+    cmd_char: Option<Characteristic>,
+    resp_char: Option<Characteristic>,
+}
+
+impl BleTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_config(config: BleConfig) -> Self {
+        Self { config, ..Self::default() }
+    }
+
+    fn service_uuid(&self) -> Uuid {
+        Uuid::from_u128(self.config.service_uuid)
+    }
+
+    async fn adapter(&mut self) -> Result<Adapter> {
+        if let Some(adapter) = &self.adapter {
+            return Ok(adapter.clone());
+        }
+        println!("Getting default adapter...");
+        let adapter = Adapter::default().await.ok_or(anyhow!("No Bluetooth adapter found"))?;
+        tokio::time::timeout(self.config.adapter_wait_timeout, adapter.wait_available())
+            .await
+            .map_err(|_| anyhow!("Bluetooth adapter did not become available"))??;
+        println!("Adapter available");
+        self.adapter = Some(adapter.clone());
+        Ok(adapter)
+    }
+}
+
+// bluest's DeviceId has no Display on Linux, where its Debug output wraps the address.
+#[cfg(target_os = "linux")]
+fn device_id(device: &Device) -> String {
+    unwrap_debug_id(&format!("{:?}", device.id()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn device_id(device: &Device) -> String {
+    device.id().to_string()
+}
+
+/// `DeviceId(AA:BB:CC:DD:EE:FF)` to the address inside, dropping quotes a string id would have.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn unwrap_debug_id(debug: &str) -> String {
+    let id = debug.strip_prefix("DeviceId(").and_then(|s| s.strip_suffix(')')).unwrap_or(debug);
+    id.strip_prefix('"').and_then(|s| s.strip_suffix('"')).unwrap_or(id).to_string()
+}
+
+fn device_info(adv_device: &AdvertisingDevice) -> DeviceInfo {
+    DeviceInfo {
+        id: device_id(&adv_device.device),
+        name: adv_device.device.name().or(adv_device.adv_data.local_name.clone()),
+        rssi: adv_device.rssi,
+    }
+}
+
+impl Z407Transport for BleTransport {
+    async fn scan(&mut self, timeout: Duration, filter: &DeviceFilter) -> Result<Option<DeviceInfo>> {
+        let adapter = self.adapter().await?;
+        println!("Starting scan for Z407 service UUID...");
+        let services = [self.service_uuid()];
+        let mut scan = adapter.scan(&services).await?;
+
+        println!("Waiting for device...");
+        let deadline = Instant::now() + timeout;
+        let mut ignored = HashSet::new();
+        while let Ok(Some(adv_device)) = timeout_at(deadline, scan.next()).await {
+            let info = device_info(&adv_device);
+            if filter.matches(&info) {
+                self.found = Some(adv_device.device);
+                return Ok(Some(info));
+            }
+            if ignored.insert(info.id.clone()) {
+                println!("Ignoring {} ({}), not the configured speaker", info.id, info.name.unwrap_or_default());
+            }
+        }
+        Ok(None)
+    }
+
+    async fn discover(&mut self, timeout: Duration) -> Result<Vec<DeviceInfo>> {
+        let adapter = self.adapter().await?;
+        let services = [self.service_uuid()];
+        let mut scan = adapter.scan(&services).await?;
+
+        let deadline = Instant::now() + timeout;
+        let mut found: HashMap<String, DeviceInfo> = HashMap::new();
+        while let Ok(Some(adv_device)) = timeout_at(deadline, scan.next()).await {
+            let info = device_info(&adv_device);
+            found.insert(info.id.clone(), info);
+        }
+        let mut found: Vec<DeviceInfo> = found.into_values().collect();
+        found.sort_by_key(|d| std::cmp::Reverse(d.rssi));
+        Ok(found)
+    }
+
+    async fn connect(&mut self, device: &DeviceInfo) -> Result<()> {
+        let adapter = self.adapter().await?;
+        let found = self.found.take()
+            .filter(|d| device_id(d) == device.id)
+            .ok_or(anyhow!("Device {} was not found by the last scan", device.id))?;
+
+        adapter.connect_device(&found).await?;
+        println!("Connected! Discovering services...");
+
+        let service = found.services().await?
+            .into_iter()
+            .find(|s| s.uuid() == self.service_uuid())
+            .ok_or(anyhow!("Service not found"))?;
+
+        let characteristics = service.characteristics().await?;
+        let cmd_char = characteristics.iter()
+            .find(|c| c.uuid() == Uuid::from_u128(self.config.command_uuid))
+            .ok_or(anyhow!("Cmd char not found"))?;
+        let resp_char = characteristics.iter()
+            .find(|c| c.uuid() == Uuid::from_u128(self.config.response_uuid))
+            .ok_or(anyhow!("Resp char not found"))?;
+
+        self.cmd_char = Some(cmd_char.clone());
+        self.resp_char = Some(resp_char.clone());
+        self.device = Some(found);
+        Ok(())
+    }
+
+    async fn subscribe(&mut self) -> Result<UnboundedReceiver<Vec<u8>>> {
+        let resp_char = self.resp_char.clone().ok_or(anyhow!("Not connected"))?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            if let Ok(mut notifs) = resp_char.notify().await {
+                println!("Notifications enabled");
+                while let Some(data_res) = notifs.next().await {
+                    if let Ok(data) = data_res {
+                        if tx.send(data).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        Ok(rx)
+    }
+
+    async fn write(&mut self, cmd: Command) -> Result<()> {
+        let cmd_char = self.cmd_char.as_ref().ok_or(anyhow!("Not connected"))?;
+        cmd_char.write(&cmd.encode()).await?;
+        Ok(())
+    }
+
+    async fn disconnect(&mut self) -> Result<()> {
+        self.cmd_char = None;
+        self.resp_char = None;
+        if let (Some(adapter), Some(device)) = (&self.adapter, self.device.take()) {
+            adapter.disconnect_device(&device).await?;
+        }
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        self.device.as_ref().is_some_and(|d| d.is_connected())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unwraps_device_ids() {
+        assert_eq!(unwrap_debug_id("DeviceId(AA:BB:CC:DD:EE:FF)"), "AA:BB:CC:DD:EE:FF");
+        assert_eq!(unwrap_debug_id("DeviceId(\"BluetoothLE#BluetoothLE00:11-aa:bb\")"), "BluetoothLE#BluetoothLE00:11-aa:bb");
+        assert_eq!(unwrap_debug_id("AA:BB:CC:DD:EE:FF"), "AA:BB:CC:DD:EE:FF");
+    }
+}
@@ -0,0 +1,131 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use super::{DeviceFilter, DeviceInfo, Z407Transport};
+use crate::protocol::Command;
+
+type Responder = Box<dyn FnMut(Command) -> Vec<Vec<u8>> + Send>;
+
+struct Shared {
+    advertising: bool,
+    connected: bool,
+    written: Vec<Command>,
+    notify_tx: Option<UnboundedSender<Vec<u8>>>,
+    responder: Option<Responder>,
+}
+
+/// In-memory transport for tests. The paired [`MemoryHandle`] plays the speaker side: it sees
+/// every written command and can push notification frames or drop the link.
+pub struct MemoryTransport {
+    shared: Arc<Mutex<Shared>>,
+}
+
+#[derive(Clone)]
+pub struct MemoryHandle {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> (MemoryTransport, MemoryHandle) {
+        let shared = Arc::new(Mutex::new(Shared {
+            advertising: true,
+            connected: false,
+            written: Vec::new(),
+            notify_tx: None,
+            responder: None,
+        }));
+        (MemoryTransport { shared: shared.clone() }, MemoryHandle { shared })
+    }
+}
+
+impl MemoryHandle {
+    pub fn set_advertising(&self, advertising: bool) {
+        self.shared.lock().unwrap().advertising = advertising;
+    }
+
+    /// Installs a callback that answers each written command with zero or more notification frames.
+    pub fn set_responder(&self, responder: impl FnMut(Command) -> Vec<Vec<u8>> + Send + 'static) {
+        self.shared.lock().unwrap().responder = Some(Box::new(responder));
+    }
+
+    pub fn notify(&self, frame: &[u8]) {
+        if let Some(tx) = &self.shared.lock().unwrap().notify_tx {
+            let _ = tx.send(frame.to_vec());
+        }
+    }
+
+    pub fn written(&self) -> Vec<Command> {
+        self.shared.lock().unwrap().written.clone()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.shared.lock().unwrap().connected
+    }
+
+    pub fn disconnect(&self) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.connected = false;
+        shared.notify_tx = None;
+    }
+}
+
+impl Z407Transport for MemoryTransport {
+    async fn scan(&mut self, timeout: Duration, filter: &DeviceFilter) -> Result<Option<DeviceInfo>> {
+        let found = self.discover(timeout).await?;
+        Ok(found.into_iter().find(|d| filter.matches(d)))
+    }
+
+    async fn discover(&mut self, _timeout: Duration) -> Result<Vec<DeviceInfo>> {
+        if !self.shared.lock().unwrap().advertising {
+            return Ok(Vec::new());
+        }
+        Ok(vec![DeviceInfo {
+            id: "memory".to_string(),
+            name: Some("Z407 (memory)".to_string()),
+            rssi: None,
+        }])
+    }
+
+    async fn connect(&mut self, _device: &DeviceInfo) -> Result<()> {
+        self.shared.lock().unwrap().connected = true;
+        Ok(())
+    }
+
+    async fn subscribe(&mut self) -> Result<UnboundedReceiver<Vec<u8>>> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.shared.lock().unwrap().notify_tx = Some(tx);
+        Ok(rx)
+    }
+
+    async fn write(&mut self, cmd: Command) -> Result<()> {
+        let mut shared = self.shared.lock().unwrap();
+        if !shared.connected {
+            bail!("Not connected");
+        }
+        shared.written.push(cmd);
+        let frames = match shared.responder.as_mut() {
+            Some(responder) => responder(cmd),
+            None => Vec::new(),
+        };
+        if let Some(tx) = &shared.notify_tx {
+            for frame in frames {
+                let _ = tx.send(frame);
+            }
+        }
+        Ok(())
+    }
+
+    async fn disconnect(&mut self) -> Result<()> {
+        let mut shared = self.shared.lock().unwrap();
+        shared.connected = false;
+        shared.notify_tx = None;
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        self.shared.lock().unwrap().connected
+    }
+}
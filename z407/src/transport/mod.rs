@@ -0,0 +1,113 @@
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::Deserialize;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use crate::protocol::Command;
+
+#[cfg(feature = "bluest")]
+mod ble;
+mod memory;
+
+#[cfg(feature = "bluest")]
+pub use ble::BleTransport;
+pub use memory::{MemoryHandle, MemoryTransport};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub id: String,
+    pub name: Option<String>,
+    pub rssi: Option<i16>,
+}
+
+/// Which speaker to accept. Empty fields match anything.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceFilter {
+    /// Bluetooth address (or the platform's device id), compared case-insensitively.
+    pub address: Option<String>,
+    pub name: Option<String>,
+}
+
+impl DeviceFilter {
+    pub fn matches(&self, device: &DeviceInfo) -> bool {
+        self.address.as_ref().is_none_or(|a| a.eq_ignore_ascii_case(&device.id))
+            && self.name.as_ref().is_none_or(|n| device.name.as_ref() == Some(n))
+    }
+}
+
+impl fmt::Display for DeviceFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.address, &self.name) {
+            (Some(a), Some(n)) => write!(f, "{} ({})", a, n),
+            (Some(a), None) => write!(f, "{}", a),
+            (None, Some(n)) => write!(f, "{:?}", n),
+            (None, None) => write!(f, "any speaker"),
+        }
+    }
+}
+
+/// A link to a Z407 speaker. `ble_loop` only talks to the speaker through this trait, so the
+/// connection logic can run against the in-memory backend on machines without Bluetooth.
+pub trait Z407Transport: Send + 'static {
+    /// Waits up to `timeout` for a speaker advertising the Z407 service that `filter` accepts,
+    /// skipping any others.
+    fn scan(
+        &mut self,
+        timeout: Duration,
+        filter: &DeviceFilter,
+    ) -> impl Future<Output = Result<Option<DeviceInfo>>> + Send;
+
+    /// Lists every speaker advertising the Z407 service within `timeout`, strongest first.
+    fn discover(&mut self, timeout: Duration) -> impl Future<Output = Result<Vec<DeviceInfo>>> + Send;
+
+    fn connect(&mut self, device: &DeviceInfo) -> impl Future<Output = Result<()>> + Send;
+
+    /// Enables notifications on the response characteristic. Raw frames arrive on the returned
+    /// channel until the link drops.
+    fn subscribe(&mut self) -> impl Future<Output = Result<UnboundedReceiver<Vec<u8>>>> + Send;
+
+    fn write(&mut self, cmd: Command) -> impl Future<Output = Result<()>> + Send;
+
+    fn disconnect(&mut self) -> impl Future<Output = Result<()>> + Send;
+
+    fn is_connected(&self) -> bool;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(id: &str, name: Option<&str>) -> DeviceInfo {
+        DeviceInfo { id: id.to_string(), name: name.map(str::to_string), rssi: None }
+    }
+
+    fn filter(address: Option<&str>, name: Option<&str>) -> DeviceFilter {
+        DeviceFilter { address: address.map(str::to_string), name: name.map(str::to_string) }
+    }
+
+    #[test]
+    fn matches_by_address_and_name() {
+        let z407 = device("AA:BB:CC:DD:EE:FF", Some("Logi Z407"));
+        assert!(filter(None, None).matches(&z407));
+        assert!(filter(Some("aa:bb:cc:dd:ee:ff"), None).matches(&z407));
+        assert!(filter(None, Some("Logi Z407")).matches(&z407));
+        assert!(filter(Some("AA:BB:CC:DD:EE:FF"), Some("Logi Z407")).matches(&z407));
+
+        assert!(!filter(Some("AA:BB:CC:DD:EE:00"), None).matches(&z407));
+        assert!(!filter(None, Some("logi z407")).matches(&z407));
+        assert!(!filter(Some("AA:BB:CC:DD:EE:FF"), Some("Z407")).matches(&z407));
+        assert!(!filter(None, Some("Logi Z407")).matches(&device("AA:BB:CC:DD:EE:FF", None)));
+    }
+
+    #[test]
+    fn describes_the_filter() {
+        assert_eq!(filter(None, None).to_string(), "any speaker");
+        assert_eq!(filter(Some("AA:BB"), None).to_string(), "AA:BB");
+        assert_eq!(filter(None, Some("Z407")).to_string(), "\"Z407\"");
+        assert_eq!(filter(Some("AA:BB"), Some("Z407")).to_string(), "AA:BB (Z407)");
+    }
+}
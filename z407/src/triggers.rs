@@ -0,0 +1,367 @@
+//! Reactions to what the speaker reports, configured as `[[trigger]]` entries:
+//!
+//! ```toml
+//! [[trigger]]
+//! when = "switched_usb"           # or "cf06", "connected", "disconnected", "input_changed"
+//! if = { volume_below = "30%" }
+//! bass = 14
+//! run = "notify-send 'Z407 on USB'"
+//! ```
+//!
+//! Each speaker's triggers are evaluated on its connection's runtime. Events that arrive while a
+//! trigger's actions run are dropped, so a trigger never reacts to its own commands, and each
+//! trigger waits out its `cooldown` before firing again.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use serde::{de, Deserialize, Deserializer};
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+
+use crate::controller::Z407Controller;
+use crate::event::Event;
+use crate::level::Level;
+use crate::preset::{LevelTarget, Preset};
+use crate::protocol::{Command, Response};
+use crate::schedule::RuleAction;
+use crate::scheduler::run_action;
+use crate::speakers::{Fleet, Speakers};
+use crate::state::{Input, Z407State};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum When {
+    Connected,
+    Disconnected,
+    /// The tracked input changed, whether from the puck or from a command.
+    InputChanged,
+    Response(Response),
+}
+
+/// Responses that can be named in `when`: every command confirmation plus the puck's input
+/// notifications.
+fn named_responses() -> impl Iterator<Item = Response> {
+    Command::ALL
+        .into_iter()
+        .map(Command::confirmation)
+        .chain([Response::SwitchedBluetooth, Response::SwitchedAux, Response::SwitchedUsb])
+}
+
+fn response_name(response: &Response) -> Option<String> {
+    serde_json::to_value(response).ok().and_then(|v| v.as_str().map(str::to_string))
+}
+
+impl FromStr for When {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        match s {
+            "connected" => return Ok(When::Connected),
+            "disconnected" => return Ok(When::Disconnected),
+            "input_changed" => return Ok(When::InputChanged),
+            _ => {}
+        }
+        if let Some(response) = named_responses().find(|r| response_name(r).as_deref() == Some(s)) {
+            return Ok(When::Response(response));
+        }
+        match hex::decode(s) {
+            Ok(bytes) if !bytes.is_empty() => Ok(When::Response(Response::decode(&bytes))),
+            _ => Err(anyhow!("Unknown trigger {:?}, expected e.g. \"switched_aux\", \"cf05\" or \"disconnected\"", s)),
+        }
+    }
+}
+
+impl fmt::Display for When {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            When::Connected => write!(f, "connected"),
+            When::Disconnected => write!(f, "disconnected"),
+            When::InputChanged => write!(f, "input_changed"),
+            When::Response(response) => match response_name(response) {
+                Some(name) => write!(f, "{}", name),
+                None => write!(f, "{}", hex::encode(response.encode())),
+            },
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for When {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Every key that is set must hold. A level condition never holds while that level is
+/// uncalibrated.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Condition {
+    pub input: Option<Input>,
+    pub volume_above: Option<LevelTarget>,
+    pub volume_below: Option<LevelTarget>,
+    pub bass_above: Option<LevelTarget>,
+    pub bass_below: Option<LevelTarget>,
+}
+
+impl Condition {
+    pub fn holds(&self, state: &Z407State) -> bool {
+        let compare = |level: Level, target: Option<LevelTarget>, above: bool| match (target, level.steps) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(target), Some(steps)) => match above {
+                true => steps > target.steps(level.max_steps),
+                false => steps < target.steps(level.max_steps),
+            },
+        };
+        self.input.is_none_or(|input| state.current_input == Some(input))
+            && compare(state.volume, self.volume_above, true)
+            && compare(state.volume, self.volume_below, false)
+            && compare(state.bass, self.bass_above, true)
+            && compare(state.bass, self.bass_below, false)
+    }
+}
+
+/// Runs, in order, `commands`, the named `preset`, the levels and input given inline, and
+/// the `run` hook.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Trigger {
+    pub when: When,
+    #[serde(default, rename = "if")]
+    pub condition: Condition,
+    /// Speaker or group to watch; every speaker when unset. Actions go to the speaker whose
+    /// event fired.
+    pub speaker: Option<String>,
+    /// `"volume_down"`, `"volume_down x5"` or `"preset quiet"`.
+    #[serde(default)]
+    pub commands: Vec<RuleAction>,
+    pub preset: Option<String>,
+    pub input: Option<Input>,
+    pub volume: Option<LevelTarget>,
+    pub bass: Option<LevelTarget>,
+    /// Shell command, with `Z407_SPEAKER`, `Z407_EVENT`, `Z407_INPUT`, `Z407_VOLUME` and
+    /// `Z407_BASS` set.
+    pub run: Option<String>,
+    #[serde(default = "default_cooldown", deserialize_with = "crate::config::duration")]
+    pub cooldown: Duration,
+}
+
+fn default_cooldown() -> Duration {
+    Duration::from_secs(1)
+}
+
+impl Trigger {
+    fn inline_preset(&self) -> Option<Preset> {
+        let preset = Preset { input: self.input, volume: self.volume, bass: self.bass };
+        (!preset.is_empty()).then_some(preset)
+    }
+
+    pub fn has_action(&self) -> bool {
+        !self.commands.is_empty() || self.preset.is_some() || self.inline_preset().is_some() || self.run.is_some()
+    }
+
+    async fn fire(&self, fleet: &Fleet, speaker: &str, ctl: &Z407Controller) -> Result<()> {
+        let mut actions = self.commands.clone();
+        actions.extend(self.preset.clone().map(RuleAction::Preset));
+        for action in &actions {
+            run_action(fleet, ctl, action).await?;
+        }
+        if let Some(preset) = self.inline_preset() {
+            ctl.apply_preset(&format!("trigger {}", self.when), &preset).await?;
+        }
+        if let Some(run) = &self.run {
+            let state = ctl.state.lock().unwrap().clone();
+            self.run_hook(run, speaker, &state).await?;
+        }
+        Ok(())
+    }
+
+    async fn run_hook(&self, run: &str, speaker: &str, state: &Z407State) -> Result<()> {
+        let (shell, flag) = if cfg!(windows) { ("cmd", "/C") } else { ("sh", "-c") };
+        let mut command = tokio::process::Command::new(shell);
+        command.arg(flag).arg(run);
+        let steps = |level: Level| level.steps.map(|s| s.to_string()).unwrap_or_default();
+        command
+            .env("Z407_SPEAKER", speaker)
+            .env("Z407_EVENT", self.when.to_string())
+            .env("Z407_INPUT", state.current_input.map(|i| i.to_string()).unwrap_or_default())
+            .env("Z407_VOLUME", steps(state.volume))
+            .env("Z407_BASS", steps(state.bass));
+        let status = command.status().await?;
+        match status.success() {
+            true => Ok(()),
+            false => Err(anyhow!("`{}` exited with {}", run, status)),
+        }
+    }
+}
+
+/// Starts watching each speaker for the triggers that name it.
+pub fn spawn(speakers: &Speakers, triggers: &[Trigger]) {
+    let fleet = speakers.fleet();
+    for speaker in speakers.iter() {
+        let watching: Vec<Trigger> = triggers
+            .iter()
+            .filter(|t| fleet.resolve(t.speaker.as_deref()).is_ok_and(|targets| targets.iter().any(|(n, _)| *n == speaker.name)))
+            .cloned()
+            .collect();
+        if !watching.is_empty() {
+            speaker.conn.runtime().spawn(watch(fleet.clone(), speaker.name.clone(), watching));
+        }
+    }
+}
+
+/// Link and input changes are taken from the state rather than from events, so a watcher that
+/// starts after the speaker connected still sees it happen once.
+async fn watch(fleet: Fleet, speaker: String, triggers: Vec<Trigger>) {
+    let Some(ctl) = fleet.get(&speaker).cloned() else { return };
+    let mut events = ctl.subscribe();
+    let mut connected = false;
+    let mut input = ctl.state.lock().unwrap().current_input;
+    let mut response = None;
+    let mut last_fired: Vec<Option<Instant>> = vec![None; triggers.len()];
+
+    loop {
+        let state = ctl.state.lock().unwrap().clone();
+        let mut happened: Vec<When> = response.take().map(When::Response).into_iter().collect();
+        if state.connected != connected {
+            connected = state.connected;
+            happened.push(if connected { When::Connected } else { When::Disconnected });
+        }
+        if state.current_input != input {
+            input = state.current_input;
+            happened.push(When::InputChanged);
+        }
+
+        let mut fired = false;
+        for (trigger, last) in triggers.iter().zip(last_fired.iter_mut()) {
+            if !happened.contains(&trigger.when) || !trigger.condition.holds(&state) {
+                continue;
+            }
+            if last.is_some_and(|at| at.elapsed() < trigger.cooldown) {
+                println!("{}: trigger {} cooling down, skipped", speaker, trigger.when);
+                continue;
+            }
+            *last = Some(Instant::now());
+            fired = true;
+            println!("{}: trigger {} fired", speaker, trigger.when);
+            if let Err(e) = trigger.fire(&fleet, &speaker, &ctl).await {
+                eprintln!("{}: trigger {} failed: {}", speaker, trigger.when, e);
+            }
+        }
+
+        // Whatever the actions above caused is not for the triggers to react to.
+        if fired {
+            while !matches!(events.try_recv(), Err(TryRecvError::Empty | TryRecvError::Closed)) {}
+            let s = ctl.state.lock().unwrap();
+            (connected, input) = (s.connected, s.current_input);
+        }
+
+        match events.recv().await {
+            Ok(Event::Response { response: r, .. }) => response = Some(r),
+            Ok(_) | Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::simulator::SimulatorHandle;
+
+    fn wait_until(what: &str, mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out waiting until {}", what);
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    /// A simulated speaker watched by the triggers in `config`, once it has connected.
+    fn watched(config: &str) -> (Speakers, SimulatorHandle, Z407Controller) {
+        let config = Config::parse(config).unwrap();
+        let (speakers, mut pucks) = Speakers::simulate_with_pucks(&config, &config.speakers());
+        let ctl = speakers.fleet().iter().next().unwrap().1.clone();
+        wait_until("connected", || ctl.state.lock().unwrap().connected);
+        spawn(&speakers, &config.triggers);
+        (speakers, pucks.remove(0), ctl)
+    }
+
+    fn responses(events: &mut tokio::sync::broadcast::Receiver<Event>) -> Vec<Response> {
+        std::iter::from_fn(|| events.try_recv().ok())
+            .filter_map(|event| match event {
+                Event::Response { response, .. } => Some(response),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_when() {
+        assert_eq!("connected".parse::<When>().unwrap(), When::Connected);
+        assert_eq!(" input_changed ".parse::<When>().unwrap(), When::InputChanged);
+        assert_eq!("switched_usb".parse::<When>().unwrap(), When::Response(Response::SwitchedUsb));
+        assert_eq!("cf06".parse::<When>().unwrap(), When::Response(Response::SwitchedUsb));
+        assert_eq!("sound1".parse::<When>().unwrap(), When::Response(Response::Sound1));
+        assert_eq!("c0ff".parse::<When>().unwrap(), When::Response(Response::Unknown(vec![0xc0, 0xff])));
+        for bad in ["", "warp", "connected_now", "zz"] {
+            assert!(bad.parse::<When>().is_err(), "{:?}", bad);
+        }
+        for when in ["disconnected", "volume_up", "c0ff"] {
+            assert_eq!(when.parse::<When>().unwrap().to_string(), when);
+        }
+    }
+
+    #[test]
+    fn checks_conditions() {
+        let mut state = Z407State { current_input: Some(Input::Aux), ..Default::default() };
+        state.volume.steps = Some(40);
+        let condition = |toml: &str| -> Condition { toml::from_str(toml).unwrap() };
+
+        assert!(Condition::default().holds(&state));
+        assert!(condition("input = \"aux\"").holds(&state));
+        assert!(!condition("input = \"usb\"").holds(&state));
+        assert!(condition("volume_above = \"70%\"").holds(&state));
+        assert!(!condition("volume_above = 40").holds(&state));
+        assert!(condition("volume_below = 41\ninput = \"aux\"").holds(&state));
+        assert!(!condition("volume_below = 41\ninput = \"usb\"").holds(&state));
+        // Bass is uncalibrated, so neither side of it holds.
+        assert!(!condition("bass_below = 20").holds(&state));
+        assert!(!condition("bass_above = 0").holds(&state));
+    }
+
+    #[test]
+    fn waits_out_the_cooldown() {
+        let (_speakers, puck, ctl) = watched("[[trigger]]\nwhen = \"play_pause\"\ncommands = [\"next_track\"]\ncooldown = \"10s\"");
+        let mut events = ctl.subscribe();
+        puck.press();
+        wait_until("the trigger fired", || ctl.state.lock().unwrap().last_response == Some(Response::NextTrack));
+        puck.press();
+        thread::sleep(Duration::from_millis(200));
+
+        let responses = responses(&mut events);
+        assert_eq!(responses.iter().filter(|r| **r == Response::PlayPause).count(), 2);
+        assert_eq!(responses.iter().filter(|r| **r == Response::NextTrack).count(), 1);
+    }
+
+    #[test]
+    fn ignores_what_its_own_actions_cause() {
+        let triggers = "[[trigger]]\nwhen = \"volume_up\"\ncommands = [\"volume_down\"]\ncooldown = \"0s\"\n\
+                        [[trigger]]\nwhen = \"volume_down\"\ncommands = [\"volume_up\"]\ncooldown = \"0s\"";
+        let (_speakers, puck, ctl) = watched(triggers);
+        let mut events = ctl.subscribe();
+        let start = puck.volume();
+        puck.twist(true);
+        wait_until("the trigger fired", || ctl.state.lock().unwrap().last_response == Some(Response::VolumeDown));
+        thread::sleep(Duration::from_millis(200));
+
+        // The twist up, the trigger's step back down, and nothing after.
+        assert_eq!(responses(&mut events), [Response::VolumeUp, Response::VolumeDown]);
+        assert_eq!(puck.volume(), start);
+    }
+}
@@ -0,0 +1,512 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::{Parser, Subcommand, ValueEnum};
+#[cfg(unix)]
+use z407::ipc::{default_socket_path, DaemonClient, DaemonRequest};
+use z407::config::{self, Config};
+use z407::transport::BleTransport;
+use z407::{
+    Command, CommandError, CommandResult, Easing, LevelKind, Preset, Schedule, Simulator, SimulatorConfig, Speakers, Z407Controller,
+    Z407Transport,
+};
+
+const EXIT_NOT_ACKNOWLEDGED: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_NOT_CONNECTED: u8 = 3;
+
+#[derive(Parser)]
+#[command(name = "z407ctl", about = "Control Logitech Z407 speakers over BLE")]
+struct Cli {
+    /// Talk to the software simulator instead of a real speaker.
+    #[arg(long, global = true)]
+    simulate: bool,
+
+    /// Config file to use instead of the default one.
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Only connect to the speaker with this address, overriding the config.
+    #[arg(long, global = true)]
+    address: Option<String>,
+
+    /// Only connect to a speaker advertising this name, overriding the config.
+    #[arg(long, global = true)]
+    name: Option<String>,
+
+    /// Speaker or group to act on, as named in the config; every speaker by default.
+    #[arg(long, global = true, default_value = "all")]
+    speaker: String,
+
+    /// Seconds to wait for the speaker to connect.
+    #[arg(long, global = true, default_value_t = 30)]
+    connect_timeout: u64,
+
+    /// Go through a running z407d instead of opening a connection, optionally at this socket.
+    #[cfg(unix)]
+    #[arg(long, global = true)]
+    daemon: Option<Option<PathBuf>>,
+
+    #[command(subcommand)]
+    task: Task,
+}
+
+#[derive(Subcommand)]
+enum Task {
+    /// List nearby speakers and their signal strength.
+    Scan {
+        /// Seconds to listen for adverts.
+        #[arg(long, default_value_t = 10)]
+        timeout: u64,
+    },
+    /// Forget the remembered last-used speaker.
+    Forget,
+    /// Apply or list the presets from the config.
+    Preset {
+        #[command(subcommand)]
+        op: PresetOp,
+    },
+    /// List the scheduled rules and when each fires next.
+    Schedule,
+    #[command(flatten)]
+    Action(Action),
+}
+
+#[derive(Subcommand)]
+enum PresetOp {
+    /// Switch input and step volume and bass to the preset's values. Ctrl-C stops it.
+    Apply { name: String },
+    List,
+    /// Stop a preset or level change running in z407d.
+    Cancel,
+}
+
+#[derive(Clone, Subcommand)]
+enum Action {
+    /// Step or set the volume.
+    Vol {
+        #[command(subcommand)]
+        op: LevelOp,
+    },
+    /// Step or set the bass.
+    Bass {
+        #[command(subcommand)]
+        op: LevelOp,
+    },
+    /// Fade the volume to 0. Ctrl-C stops it.
+    FadeOut {
+        #[command(flatten)]
+        fade: FadeArgs,
+    },
+    /// Drop the volume to 0, then fade it up to `target`.
+    FadeIn {
+        target: u8,
+        #[command(flatten)]
+        fade: FadeArgs,
+    },
+    /// Switch the input source.
+    Input { input: InputArg },
+    PlayPause,
+    Next,
+    Prev,
+    /// Enter Bluetooth pairing mode.
+    Pairing,
+    /// Play one of the speaker's chimes.
+    Chime {
+        #[arg(value_parser = clap::value_parser!(u8).range(1..=3))]
+        number: u8,
+    },
+    /// Reset the speaker to its defaults.
+    FactoryReset {
+        /// Required, since this cannot be undone.
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Built from `preset apply` when not going through z407d.
+    #[command(skip)]
+    ApplyPreset { name: String, preset: Preset },
+}
+
+#[derive(Clone, Copy, Subcommand)]
+enum LevelOp {
+    Up {
+        #[arg(long, default_value_t = 1)]
+        steps: u32,
+    },
+    Down {
+        #[arg(long, default_value_t = 1)]
+        steps: u32,
+    },
+    /// Move to an absolute step count, calibrating first if the level is unknown.
+    Set { target: u8 },
+    /// Drive the level to its floor so later steps can be counted.
+    Calibrate,
+    /// Step gradually to `target`, calibrating first if the level is unknown.
+    Fade {
+        target: u8,
+        #[command(flatten)]
+        fade: FadeArgs,
+    },
+}
+
+#[derive(Clone, Copy, clap::Args)]
+struct FadeArgs {
+    /// How long the fade takes, e.g. `30s`, `10m` or `1.5h`.
+    #[arg(long, default_value = "10s", value_parser = config::parse_duration)]
+    over: Duration,
+    /// linear, ease-in, ease-out or ease-in-out.
+    #[arg(long, default_value_t = Easing::default())]
+    easing: Easing,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum InputArg {
+    #[value(alias = "bluetooth")]
+    Bt,
+    Aux,
+    Usb,
+}
+
+impl InputArg {
+    fn command(self) -> Command {
+        match self {
+            InputArg::Bt => Command::SwitchBluetooth,
+            InputArg::Aux => Command::SwitchAux,
+            InputArg::Usb => Command::SwitchUsb,
+        }
+    }
+}
+
+async fn step(ctl: &Z407Controller, cmd: Command, steps: u32) -> CommandResult {
+    for _ in 0..steps {
+        ctl.execute(cmd).await?;
+    }
+    Ok(())
+}
+
+async fn level_op(ctl: &Z407Controller, kind: LevelKind, op: LevelOp) -> CommandResult {
+    match op {
+        LevelOp::Up { steps } => step(ctl, kind.up(), steps).await,
+        LevelOp::Down { steps } => step(ctl, kind.down(), steps).await,
+        LevelOp::Set { target } => ctl.set_level(kind, target).await,
+        LevelOp::Calibrate => ctl.calibrate(kind).await,
+        LevelOp::Fade { target, fade } => ctl.fade_to(kind, target, fade.over, fade.easing).await,
+    }
+}
+
+async fn run(ctl: Z407Controller, action: Action) -> CommandResult {
+    match action {
+        Action::Vol { op } => level_op(&ctl, LevelKind::Volume, op).await,
+        Action::Bass { op } => level_op(&ctl, LevelKind::Bass, op).await,
+        Action::FadeOut { fade } => ctl.fade_out(fade.over, fade.easing).await,
+        Action::FadeIn { target, fade } => ctl.fade_in(target, fade.over, fade.easing).await,
+        Action::Input { input } => ctl.execute(input.command()).await,
+        Action::PlayPause => ctl.execute(Command::PlayPause).await,
+        Action::Next => ctl.execute(Command::NextTrack).await,
+        Action::Prev => ctl.execute(Command::PrevTrack).await,
+        Action::Pairing => ctl.execute(Command::Pairing).await,
+        Action::Chime { number } => ctl.execute(chime(number)).await,
+        Action::FactoryReset { .. } => ctl.execute(Command::FactoryReset).await,
+        Action::ApplyPreset { name, preset } => ctl.apply_preset(&name, &preset).await,
+    }
+}
+
+fn chime(number: u8) -> Command {
+    match number {
+        1 => Command::Sound1,
+        2 => Command::Sound2,
+        _ => Command::Sound3,
+    }
+}
+
+#[cfg(unix)]
+fn level_request(kind: LevelKind, op: LevelOp) -> DaemonRequest {
+    match op {
+        LevelOp::Up { steps } => DaemonRequest::Command { command: kind.up(), steps: Some(steps) },
+        LevelOp::Down { steps } => DaemonRequest::Command { command: kind.down(), steps: Some(steps) },
+        LevelOp::Set { target } => DaemonRequest::SetLevel { level: kind, target },
+        LevelOp::Calibrate => DaemonRequest::Calibrate { level: kind },
+        LevelOp::Fade { target, fade } => {
+            DaemonRequest::Fade { level: kind, target, over_ms: fade.over.as_millis() as u64, easing: fade.easing }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn daemon_request(action: Action) -> DaemonRequest {
+    let command = match action {
+        Action::Vol { op } => return level_request(LevelKind::Volume, op),
+        Action::Bass { op } => return level_request(LevelKind::Bass, op),
+        Action::FadeOut { fade } => {
+            let over_ms = fade.over.as_millis() as u64;
+            return DaemonRequest::Fade { level: LevelKind::Volume, target: 0, over_ms, easing: fade.easing };
+        }
+        Action::FadeIn { target, fade } => {
+            return DaemonRequest::FadeIn { target, over_ms: fade.over.as_millis() as u64, easing: fade.easing };
+        }
+        Action::Input { input } => input.command(),
+        Action::PlayPause => Command::PlayPause,
+        Action::Next => Command::NextTrack,
+        Action::Prev => Command::PrevTrack,
+        Action::Pairing => Command::Pairing,
+        Action::Chime { number } => chime(number),
+        Action::FactoryReset { .. } => Command::FactoryReset,
+        Action::ApplyPreset { name, .. } => return DaemonRequest::Preset { name },
+    };
+    DaemonRequest::Command { command, steps: None }
+}
+
+#[cfg(unix)]
+fn run_via_daemon(path: PathBuf, speaker: &str, request: DaemonRequest) -> ExitCode {
+    let mut client = match DaemonClient::connect(&path) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::from(EXIT_NOT_CONNECTED);
+        }
+    };
+    match client.request_to(Some(speaker), request) {
+        Ok(resp) if resp.ok => ExitCode::SUCCESS,
+        Ok(resp) => {
+            eprintln!("Command not acknowledged: {}", resp.error.as_deref().unwrap_or_default());
+            ExitCode::from(if resp.connected() { EXIT_NOT_ACKNOWLEDGED } else { EXIT_NOT_CONNECTED })
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::from(EXIT_NOT_CONNECTED)
+        }
+    }
+}
+
+fn scan(config: &Config, simulate: bool, timeout: Duration) -> ExitCode {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let found = if simulate {
+        let (mut simulator, _handle) = Simulator::new(SimulatorConfig::default());
+        runtime.block_on(simulator.discover(timeout))
+    } else {
+        let mut transport = BleTransport::with_config(config.ble.clone());
+        runtime.block_on(transport.discover(timeout))
+    };
+    let filters: Vec<_> = match config.speakers.is_empty() {
+        true => vec![(String::new(), config.ble_connection_options().device)],
+        false => config.speakers.iter().map(|s| (format!(" ({})", s.name), s.filter())).collect(),
+    };
+    let found = match found {
+        Ok(found) => found,
+        Err(e) => {
+            eprintln!("Scan failed: {}", e);
+            return ExitCode::from(EXIT_NOT_CONNECTED);
+        }
+    };
+    if found.is_empty() {
+        eprintln!("No Z407 speakers found");
+        return ExitCode::from(EXIT_NOT_CONNECTED);
+    }
+    for device in found {
+        let rssi = device.rssi.map(|r| format!("{} dBm", r)).unwrap_or_else(|| "?".to_string());
+        let speaker = filters.iter().find(|(_, filter)| filter.matches(&device)).map(|(name, _)| name.as_str());
+        let marker = if speaker.is_some() { "*" } else { " " };
+        let name = device.name.as_deref().unwrap_or_default();
+        println!("{} {:<20} {:>8}  {}{}", marker, device.id, rssi, name, speaker.unwrap_or_default());
+    }
+    ExitCode::SUCCESS
+}
+
+fn list_presets(config: &Config) -> ExitCode {
+    if config.presets.is_empty() {
+        eprintln!("No presets configured");
+    }
+    for (name, preset) in &config.presets {
+        println!("{:<16} {}", name, preset);
+    }
+    ExitCode::SUCCESS
+}
+
+fn list_schedule(config: &Config) -> ExitCode {
+    let Some(path) = config.scheduler.path() else {
+        eprintln!("No schedule file; set `file` under [scheduler]");
+        return ExitCode::from(EXIT_USAGE);
+    };
+    let schedule = match Schedule::load(&path) {
+        Ok(schedule) => schedule,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+    if schedule.rules.is_empty() {
+        eprintln!("No rules in {}", path.display());
+    }
+    let now = chrono::Local::now();
+    for rule in &schedule.rules {
+        let next = match (rule.enabled, rule.next_after(now)) {
+            (false, _) => "disabled".to_string(),
+            (true, Some(at)) => at.format("%a %Y-%m-%d %H:%M").to_string(),
+            (true, None) => "never".to_string(),
+        };
+        let action = rule.action().map(|a| a.to_string()).unwrap_or_default();
+        let speaker = rule.speaker.as_deref().unwrap_or("all");
+        println!("{:<16} {:<16} {:<24} {:<10} {:<8} {}", rule.name, rule.cron, next, speaker, rule.if_disconnected, action);
+    }
+    ExitCode::SUCCESS
+}
+
+/// Rewrites one stderr line with every speaker's preset progress until the tasks finish.
+fn show_progress(speakers: &Speakers, finished: impl Fn() -> bool) {
+    let mut last = String::new();
+    while !finished() {
+        let parts: Vec<String> = speakers
+            .iter()
+            .filter_map(|s| {
+                let p = s.conn.snapshot().preset?;
+                let who = if speakers.len() > 1 { format!("{} ", s.name) } else { String::new() };
+                Some(format!("{}{} {}/{}", who, p.name, p.done, p.total))
+            })
+            .collect();
+        let line = parts.join("  ");
+        if line != last {
+            eprint!("\r{:<width$}", line, width = last.len());
+            let _ = std::io::stderr().flush();
+            last = line;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    if !last.is_empty() {
+        eprintln!();
+    }
+}
+
+fn forget() -> ExitCode {
+    let Some(path) = config::last_device_path() else {
+        return ExitCode::SUCCESS;
+    };
+    match config::forget_device(&path) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Could not forget the last speaker: {}", e);
+            ExitCode::from(EXIT_USAGE)
+        }
+    }
+}
+
+fn wait_connected(speakers: &Speakers, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if speakers.iter().all(|s| s.conn.snapshot().connected) {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    false
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    let mut config = match Config::load(cli.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+    if cli.address.is_some() || cli.name.is_some() {
+        config.device.address = cli.address;
+        config.device.name = cli.name;
+        config.speakers.clear();
+    }
+
+    #[cfg(unix)]
+    let daemon = cli.daemon.map(|path| path.unwrap_or_else(default_socket_path));
+
+    let action = match cli.task {
+        Task::Scan { timeout } => return scan(&config, cli.simulate, Duration::from_secs(timeout)),
+        Task::Forget => return forget(),
+        Task::Preset { op: PresetOp::List } => return list_presets(&config),
+        Task::Schedule => return list_schedule(&config),
+        Task::Preset { op } => {
+            // z407d applies presets from its own config.
+            #[cfg(unix)]
+            if let Some(path) = daemon {
+                let request = match op {
+                    PresetOp::Apply { name } => DaemonRequest::Preset { name },
+                    _ => DaemonRequest::Cancel,
+                };
+                return run_via_daemon(path, &cli.speaker, request);
+            }
+            let PresetOp::Apply { name } = op else {
+                eprintln!("Nothing to cancel without --daemon; a local preset stops when z407ctl exits");
+                return ExitCode::from(EXIT_USAGE);
+            };
+            match config.preset(&name) {
+                Ok(preset) => Action::ApplyPreset { preset: preset.clone(), name },
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return ExitCode::from(EXIT_USAGE);
+                }
+            }
+        }
+        Task::Action(action) => action,
+    };
+
+    if let Action::FactoryReset { yes: false } = action {
+        eprintln!("Refusing to factory reset without --yes");
+        return ExitCode::from(EXIT_USAGE);
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = daemon {
+        return run_via_daemon(path, &cli.speaker, daemon_request(action));
+    }
+
+    let targets = match config.resolve(Some(&cli.speaker)) {
+        Ok(targets) => targets,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+    let speakers = if cli.simulate {
+        Speakers::simulate(&config, &targets)
+    } else {
+        Speakers::spawn(&config, &targets)
+    };
+    // Name each speaker in messages once there is more than one.
+    let label = |name: &str| if speakers.len() > 1 { format!("{}: ", name) } else { String::new() };
+
+    let mut code = ExitCode::SUCCESS;
+    if !wait_connected(&speakers, Duration::from_secs(cli.connect_timeout)) {
+        for speaker in speakers.iter() {
+            let state = speaker.conn.snapshot();
+            if !state.connected {
+                let reason = state.last_error.unwrap_or_else(|| "timed out".to_string());
+                eprintln!("{}Could not connect to Z407: {}", label(&speaker.name), reason);
+            }
+        }
+        code = ExitCode::from(EXIT_NOT_CONNECTED);
+    }
+
+    let tasks: Vec<_> = speakers
+        .iter()
+        .filter(|s| s.conn.snapshot().connected)
+        .map(|s| (s.name.as_str(), speakers.runtime().spawn(run(s.conn.controller(), action.clone()))))
+        .collect();
+    if let Action::ApplyPreset { .. } = action {
+        show_progress(&speakers, || tasks.iter().all(|(_, task)| task.is_finished()));
+    }
+    for (name, task) in tasks {
+        if let Ok(Err(e)) = speakers.runtime().block_on(task) {
+            match e {
+                CommandError::AtLimit { .. } | CommandError::Uncalibrated(_) => eprintln!("{}Refused: {}", label(name), e),
+                _ => eprintln!("{}Command not acknowledged: {}", label(name), e),
+            }
+            if code == ExitCode::SUCCESS {
+                code = ExitCode::from(EXIT_NOT_ACKNOWLEDGED);
+            }
+        }
+    }
+    code
+}
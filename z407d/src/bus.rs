@@ -0,0 +1,9 @@
+/// `name` as a D-Bus bus-name or object-path element, which only allow ASCII letters, digits
+/// and `_`, and may not start with a digit.
+pub fn element(name: &str) -> String {
+    let mut element: String = name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    if !element.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        element.insert(0, '_');
+    }
+    element
+}
@@ -0,0 +1,329 @@
+use std::collections::BTreeMap;
+use std::fs;
+#[cfg(feature = "http")]
+use std::net::SocketAddr;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use clap::Parser;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task::JoinSet;
+use tokio::time::interval;
+use z407::ipc::{default_socket_path, DaemonRequest, DaemonResponse, RequestEnvelope, StateSnapshot};
+use z407::{scheduler, triggers, CommandResult, Config, Fleet, Speakers, SystemClock, Z407Controller};
+
+#[derive(Parser)]
+#[command(name = "z407d", about = "Hold the Z407 connection and serve it to local clients")]
+struct Cli {
+    /// Unix socket to listen on. Defaults to $XDG_RUNTIME_DIR/z407.sock.
+    #[arg(long)]
+    socket: Option<PathBuf>,
+
+    /// Config file to use instead of the default one.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Serve the software simulator instead of a real speaker.
+    #[arg(long)]
+    simulate: bool,
+
+    /// Also serve the REST API on this address, e.g. 127.0.0.1:8407.
+    #[cfg(feature = "http")]
+    #[arg(long)]
+    http: Option<SocketAddr>,
+
+    /// Also bridge to the MQTT broker at HOST[:PORT] or [IPV6]:PORT.
+    #[cfg(feature = "mqtt")]
+    #[arg(long)]
+    mqtt: Option<String>,
+
+    /// Base MQTT topic and Home Assistant node id.
+    #[cfg(feature = "mqtt")]
+    #[arg(long, default_value = "z407")]
+    mqtt_topic: String,
+
+    #[cfg(feature = "mqtt")]
+    #[arg(long, default_value = "homeassistant")]
+    mqtt_discovery_prefix: String,
+
+    /// Register as an MPRIS2 player on the session bus.
+    #[cfg(all(target_os = "linux", feature = "mpris"))]
+    #[arg(long)]
+    mpris: bool,
+
+    /// Serve org.z407.Controller on the session bus.
+    #[cfg(all(target_os = "linux", feature = "dbus"))]
+    #[arg(long)]
+    dbus: bool,
+}
+
+/// Presets come from `fleet`, which `ctl` belongs to. The handshake is the connection's own
+/// business, so its commands are refused.
+pub(crate) async fn execute(fleet: &Fleet, ctl: &Z407Controller, request: DaemonRequest) -> DaemonResponse {
+    let result: CommandResult = match request {
+        DaemonRequest::Command { command, .. } if command.is_handshake() => {
+            return DaemonResponse::error(None, format!("{:?} is only sent by the connection itself", command));
+        }
+        DaemonRequest::Command { command, steps } => {
+            let mut result = Ok(());
+            for _ in 0..steps.unwrap_or(1) {
+                result = ctl.execute(command).await;
+                if result.is_err() {
+                    break;
+                }
+            }
+            result
+        }
+        DaemonRequest::SetLevel { level, target } => ctl.set_level(level, target).await,
+        DaemonRequest::Calibrate { level } => ctl.calibrate(level).await,
+        DaemonRequest::Fade { level, target, over_ms, easing } => {
+            ctl.fade_to(level, target, Duration::from_millis(over_ms), easing).await
+        }
+        DaemonRequest::FadeIn { target, over_ms, easing } => {
+            ctl.fade_in(target, Duration::from_millis(over_ms), easing).await
+        }
+        DaemonRequest::Connect => {
+            ctl.request_scan();
+            Ok(())
+        }
+        DaemonRequest::Preset { name } => match fleet.preset(&name) {
+            Ok(preset) => ctl.apply_preset(&name, preset).await,
+            Err(e) => return DaemonResponse::error(None, e),
+        },
+        DaemonRequest::Cancel => {
+            ctl.cancel();
+            Ok(())
+        }
+        DaemonRequest::State => Ok(()),
+    };
+    let mut response = match result {
+        Ok(()) => DaemonResponse::ok(None),
+        Err(e) => DaemonResponse::error(None, e),
+    };
+    response.state = Some(StateSnapshot::from(&*ctl.state.lock().unwrap()));
+    response
+}
+
+/// `execute` on every speaker `target` names, concurrently. A single speaker answers as
+/// `execute` does; several answer with `states` and every failure in `error`.
+pub(crate) async fn execute_on(fleet: &Fleet, target: Option<&str>, request: DaemonRequest) -> DaemonResponse {
+    let targets = match fleet.resolve(target) {
+        Ok(targets) => targets,
+        Err(e) => return DaemonResponse::error(None, e),
+    };
+    if let [(_, ctl)] = targets[..] {
+        return execute(fleet, ctl, request).await;
+    }
+
+    let mut tasks = JoinSet::new();
+    for (name, ctl) in targets {
+        let (name, fleet, ctl, request) = (name.to_string(), fleet.clone(), ctl.clone(), request.clone());
+        tasks.spawn(async move { (name, execute(&fleet, &ctl, request).await) });
+    }
+    let mut errors = Vec::new();
+    let mut states = BTreeMap::new();
+    while let Some(joined) = tasks.join_next().await {
+        let Ok((name, response)) = joined else { continue };
+        if let Some(error) = response.error {
+            errors.push(format!("{}: {}", name, error));
+        }
+        if let Some(state) = response.state {
+            states.insert(name, state);
+        }
+    }
+    errors.sort();
+    let mut response = match errors.is_empty() {
+        true => DaemonResponse::ok(None),
+        false => DaemonResponse::error(None, errors.join("; ")),
+    };
+    response.states = Some(states);
+    response
+}
+
+async fn serve_client(stream: UnixStream, fleet: Fleet) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<RequestEnvelope>(&line) {
+            Ok(envelope) => {
+                let response = execute_on(&fleet, envelope.speaker.as_deref(), envelope.request).await;
+                DaemonResponse { id: envelope.id, ..response }
+            }
+            Err(e) => DaemonResponse::error(None, format!("Invalid request: {}", e)),
+        };
+        let mut out = serde_json::to_string(&response)?;
+        out.push('\n');
+        writer.write_all(out.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Binds `path` so only this user can connect: the socket is made 0600 in a private directory
+/// and only then moved into place, which matters when it falls back to a shared temp dir.
+fn bind_private(path: &Path) -> Result<UnixListener> {
+    let dir = path.with_extension(format!("{}.tmp", std::process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let staged = dir.join("z407.sock");
+    let bound = UnixListener::bind(&staged).map_err(anyhow::Error::from).and_then(|listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&staged);
+    let _ = fs::remove_dir(&dir);
+    bound
+}
+
+async fn serve(speakers: &Speakers, path: &Path) -> Result<()> {
+    let listener = bind_private(path)?;
+    println!("Listening on {}", path.display());
+
+    // Nobody reads the decoded responses here; drain them so they don't pile up.
+    let mut tick = interval(Duration::from_millis(100));
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = accepted?;
+                let fleet = speakers.fleet();
+                tokio::spawn(async move {
+                    if let Err(e) = serve_client(stream, fleet).await {
+                        eprintln!("Client error: {}", e);
+                    }
+                });
+            }
+            _ = tick.tick() => {
+                speakers.responses();
+            }
+        }
+    }
+}
+
+/// Removes a socket left behind by a daemon that is gone. A daemon still answering keeps it,
+/// since the speaker only takes one connection anyway.
+fn claim_socket(path: &Path) -> Result<()> {
+    let Ok(metadata) = fs::symlink_metadata(path) else { return Ok(()) };
+    if !metadata.file_type().is_socket() {
+        bail!("{} exists and is not a socket", path.display());
+    }
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        bail!("Another z407d is already listening on {}", path.display());
+    }
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+pub fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let config = Config::load(cli.config.as_deref())?;
+    let path = cli.socket.unwrap_or_else(default_socket_path);
+
+    claim_socket(&path)?;
+
+    let speakers = if cli.simulate {
+        Speakers::simulate(&config, &config.speakers())
+    } else {
+        Speakers::spawn(&config, &config.speakers())
+    };
+    triggers::spawn(&speakers, &config.triggers);
+    if let (true, Some(schedule)) = (config.scheduler.enabled, config.scheduler.path()) {
+        speakers.runtime().spawn(scheduler::run(speakers.fleet(), schedule, SystemClock));
+    }
+
+    #[cfg(feature = "http")]
+    if let Some(addr) = cli.http {
+        let fleet = speakers.fleet();
+        speakers.runtime().spawn(async move {
+            if let Err(e) = crate::http::serve(addr, fleet).await {
+                eprintln!("HTTP API stopped: {}", e);
+            }
+        });
+    }
+
+    // One bridge per speaker, under `<topic>/<speaker>` once there are several.
+    #[cfg(feature = "mqtt")]
+    if let Some(broker) = cli.mqtt {
+        let (host, port) = crate::mqtt::MqttConfig::parse_broker(&broker)?;
+        for speaker in speakers.iter() {
+            let several = speakers.len() > 1;
+            let config = crate::mqtt::MqttConfig {
+                host: host.clone(),
+                port,
+                topic: match several {
+                    true => format!("{}/{}", cli.mqtt_topic, speaker.name),
+                    false => cli.mqtt_topic.clone(),
+                },
+                discovery_prefix: cli.mqtt_discovery_prefix.clone(),
+                speaker: several.then(|| speaker.name.clone()),
+            };
+            let (fleet, ctl) = (speakers.fleet(), speaker.conn.controller());
+            speaker.conn.runtime().spawn(async move {
+                if let Err(e) = crate::mqtt::run(config, fleet, ctl).await {
+                    eprintln!("MQTT bridge stopped: {}", e);
+                }
+            });
+        }
+    }
+
+    #[cfg(all(target_os = "linux", feature = "mpris"))]
+    if cli.mpris {
+        for speaker in speakers.iter() {
+            let instance = (speakers.len() > 1).then(|| speaker.name.clone());
+            let ctl = speaker.conn.controller();
+            speaker.conn.runtime().spawn(async move {
+                if let Err(e) = crate::mpris::run(ctl, instance).await {
+                    eprintln!("MPRIS player stopped: {}", e);
+                }
+            });
+        }
+    }
+
+    #[cfg(all(target_os = "linux", feature = "dbus"))]
+    if cli.dbus {
+        let fleet = speakers.fleet();
+        speakers.runtime().spawn(async move {
+            if let Err(e) = crate::dbus::run(fleet).await {
+                eprintln!("D-Bus service stopped: {}", e);
+            }
+        });
+    }
+
+    speakers.runtime().block_on(serve(&speakers, &path))
+}
+
+#[cfg(test)]
+mod tests {
+    use z407::{Command, Event, Response};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn refuses_handshake_commands() {
+        let config = Config::default();
+        let speakers = Speakers::simulate(&config, &config.speakers());
+        let fleet = speakers.fleet();
+        let ctl = fleet.iter().next().unwrap().1.clone();
+        for _ in 0..200 {
+            if ctl.state.lock().unwrap().connected {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let mut events = ctl.subscribe();
+
+        for command in [Command::Initiate, Command::Acknowledge] {
+            let response = execute(&fleet, &ctl, DaemonRequest::Command { command, steps: None }).await;
+            assert!(response.error.is_some_and(|e| e.contains("connection itself")), "{:?} was accepted", command);
+        }
+        let response = execute(&fleet, &ctl, DaemonRequest::Command { command: Command::PlayPause, steps: None }).await;
+        assert_eq!(response.error, None);
+        let responses: Vec<Event> = std::iter::from_fn(|| events.try_recv().ok()).collect();
+        assert!(responses.iter().all(|e| !matches!(e, Event::Handshake { .. } | Event::Response { response: Response::Initiated, .. })));
+    }
+}
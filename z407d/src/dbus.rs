@@ -0,0 +1,338 @@
+//! Optional `org.z407.Controller` service on the session bus, for desktop tools that want the
+//! speaker without speaking BLE. With several speakers, `/org/z407/Controller` drives all of them
+//! and each speaker and group gets an object below it, e.g. `/org/z407/Controller/kitchen`.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use serde_json::Value;
+use tokio::time::interval;
+use z407::ipc::DaemonRequest;
+use z407::speakers::ALL;
+use z407::{Command, Event, Fleet, Input, Level, LevelKind, Z407State};
+use zbus::object_server::SignalContext;
+use zbus::{connection, fdo, interface, Connection};
+
+use crate::bus;
+use crate::daemon::execute_on;
+
+const BUS_NAME: &str = "org.z407.Controller";
+const OBJECT_PATH: &str = "/org/z407/Controller";
+
+struct Controller {
+    fleet: Fleet,
+    /// Speaker or group name; every speaker when `None`.
+    target: Option<String>,
+}
+
+/// Steps as an `i32`, or -1 while the level is uncalibrated.
+fn steps(level: Level) -> i32 {
+    level.steps.map_or(-1, i32::from)
+}
+
+fn input_name(state: &Z407State) -> String {
+    state.current_input.map(|i| i.to_string().to_lowercase()).unwrap_or_default()
+}
+
+/// The value every state agrees on, or `fallback` when they differ.
+fn common<T: PartialEq>(states: &[Z407State], value: impl Fn(&Z407State) -> T, fallback: T) -> T {
+    let mut values = states.iter().map(value);
+    match values.next() {
+        Some(first) if values.all(|v| v == first) => first,
+        _ => fallback,
+    }
+}
+
+impl Controller {
+    async fn run(&self, request: DaemonRequest) -> fdo::Result<()> {
+        let response = execute_on(&self.fleet, self.target.as_deref(), request).await;
+        match response.error {
+            Some(error) => Err(fdo::Error::Failed(error)),
+            None => Ok(()),
+        }
+    }
+
+    async fn run_command(&self, command: Command) -> fdo::Result<()> {
+        self.run(DaemonRequest::Command { command, steps: None }).await
+    }
+
+    async fn run_confirmed(&self, command: Command, confirm: bool) -> fdo::Result<()> {
+        if !confirm {
+            return Err(fdo::Error::InvalidArgs(format!("{:?} requires confirm=true", command)));
+        }
+        self.run_command(command).await
+    }
+
+    /// Positive `steps` go up, negative go down.
+    async fn step(&self, kind: LevelKind, steps: i32) -> fdo::Result<()> {
+        let command = if steps >= 0 { kind.up() } else { kind.down() };
+        self.run(DaemonRequest::Command { command, steps: Some(steps.unsigned_abs()) }).await
+    }
+
+    fn states(&self) -> Vec<Z407State> {
+        let targets = self.fleet.resolve(self.target.as_deref()).unwrap_or_default();
+        targets.iter().map(|(_, ctl)| ctl.state.lock().unwrap().clone()).collect()
+    }
+
+    /// The property values, in order, for spotting changes.
+    fn properties(&self) -> (bool, String, i32, i32) {
+        (self.connected(), self.current_input(), self.volume(), self.bass())
+    }
+}
+
+#[interface(name = "org.z407.Controller")]
+impl Controller {
+    /// `input` is `bluetooth`, `aux` or `usb`.
+    async fn switch_input(&self, input: &str) -> fdo::Result<()> {
+        let input: Input = input.parse().map_err(|e: anyhow::Error| fdo::Error::InvalidArgs(e.to_string()))?;
+        self.run_command(input.command()).await
+    }
+
+    async fn play_chime(&self, number: u8) -> fdo::Result<()> {
+        let command = match number {
+            1 => Command::Sound1,
+            2 => Command::Sound2,
+            3 => Command::Sound3,
+            _ => return Err(fdo::Error::InvalidArgs("Chime must be 1, 2 or 3".to_string())),
+        };
+        self.run_command(command).await
+    }
+
+    async fn step_volume(&self, steps: i32) -> fdo::Result<()> {
+        self.step(LevelKind::Volume, steps).await
+    }
+
+    async fn step_bass(&self, steps: i32) -> fdo::Result<()> {
+        self.step(LevelKind::Bass, steps).await
+    }
+
+    async fn set_volume(&self, target: u8) -> fdo::Result<()> {
+        self.run(DaemonRequest::SetLevel { level: LevelKind::Volume, target }).await
+    }
+
+    async fn set_bass(&self, target: u8) -> fdo::Result<()> {
+        self.run(DaemonRequest::SetLevel { level: LevelKind::Bass, target }).await
+    }
+
+    async fn calibrate_volume(&self) -> fdo::Result<()> {
+        self.run(DaemonRequest::Calibrate { level: LevelKind::Volume }).await
+    }
+
+    async fn calibrate_bass(&self) -> fdo::Result<()> {
+        self.run(DaemonRequest::Calibrate { level: LevelKind::Bass }).await
+    }
+
+    /// Returns once the preset has been applied, or fails if it was cancelled.
+    async fn apply_preset(&self, name: &str) -> fdo::Result<()> {
+        self.run(DaemonRequest::Preset { name: name.to_string() }).await
+    }
+
+    async fn cancel(&self) -> fdo::Result<()> {
+        self.run(DaemonRequest::Cancel).await
+    }
+
+    async fn play_pause(&self) -> fdo::Result<()> {
+        self.run_command(Command::PlayPause).await
+    }
+
+    async fn next_track(&self) -> fdo::Result<()> {
+        self.run_command(Command::NextTrack).await
+    }
+
+    async fn prev_track(&self) -> fdo::Result<()> {
+        self.run_command(Command::PrevTrack).await
+    }
+
+    /// Drops the current link, so `confirm` must be true.
+    async fn enter_pairing(&self, confirm: bool) -> fdo::Result<()> {
+        self.run_confirmed(Command::Pairing, confirm).await
+    }
+
+    /// Wipes the speaker's settings, so `confirm` must be true.
+    async fn factory_reset(&self, confirm: bool) -> fdo::Result<()> {
+        self.run_confirmed(Command::FactoryReset, confirm).await
+    }
+
+    /// The speaker or group this object drives, or `all`.
+    #[zbus(property)]
+    fn name(&self) -> String {
+        self.target.clone().unwrap_or_else(|| ALL.to_string())
+    }
+
+    /// True once every speaker this object drives is connected.
+    #[zbus(property)]
+    fn connected(&self) -> bool {
+        let states = self.states();
+        !states.is_empty() && states.iter().all(|s| s.connected)
+    }
+
+    /// `bluetooth`, `aux`, `usb`, or empty while unknown or when the speakers disagree.
+    #[zbus(property)]
+    fn current_input(&self) -> String {
+        common(&self.states(), input_name, String::new())
+    }
+
+    #[zbus(property)]
+    fn volume(&self) -> i32 {
+        common(&self.states(), |s| steps(s.volume), -1)
+    }
+
+    #[zbus(property)]
+    fn bass(&self) -> i32 {
+        common(&self.states(), |s| steps(s.bass), -1)
+    }
+
+    /// Every decoded speaker notification, e.g. `("switched_aux", "cf05", "kitchen")`.
+    #[zbus(signal)]
+    async fn notification(ctxt: &SignalContext<'_>, name: &str, hex: &str, speaker: &str) -> zbus::Result<()>;
+}
+
+pub async fn run(fleet: Fleet) -> Result<()> {
+    serve(connection::Builder::session()?, fleet).await
+}
+
+async fn serve(builder: connection::Builder<'_>, fleet: Fleet) -> Result<()> {
+    let mut objects = vec![(OBJECT_PATH.to_string(), None)];
+    if fleet.len() > 1 {
+        let names = fleet.iter().map(|(name, _)| name).chain(fleet.groups());
+        objects.extend(names.map(|name| (format!("{}/{}", OBJECT_PATH, bus::element(name)), Some(name.to_string()))));
+    }
+
+    let mut builder = builder.name(BUS_NAME)?;
+    for (path, target) in &objects {
+        builder = builder.serve_at(path.as_str(), Controller { fleet: fleet.clone(), target: target.clone() })?;
+    }
+    let conn = builder.build().await?;
+    println!("D-Bus service registered as {}", BUS_NAME);
+
+    let mut watchers = tokio::task::JoinSet::new();
+    for (path, _) in objects {
+        let conn = conn.clone();
+        watchers.spawn(async move { watch(&conn, &path).await });
+    }
+    while let Some(joined) = watchers.join_next().await {
+        joined??;
+    }
+    Ok(())
+}
+
+/// Signals notifications and property changes for the object at `path`.
+async fn watch(conn: &Connection, path: &str) -> Result<()> {
+    let iface = conn.object_server().interface::<_, Controller>(path).await?;
+    let ctxt = iface.signal_context();
+    let mut events = {
+        let controller = iface.get().await;
+        controller.fleet.subscribe(controller.target.as_deref())?
+    };
+    let mut last = iface.get().await.properties();
+    // Calibration and disconnects change the state without a notification, so poll as well.
+    let mut refresh = interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Some((speaker, Event::Response { response, hex })) => {
+                    let name = match serde_json::to_value(&response) {
+                        Ok(Value::String(name)) => name,
+                        _ => "unknown".to_string(),
+                    };
+                    Controller::notification(ctxt, &name, &hex, &speaker).await?;
+                }
+                Some(_) => {}
+                None => return Ok(()),
+            },
+            _ = refresh.tick() => {}
+        }
+
+        let controller = iface.get().await;
+        let current = controller.properties();
+        if current.0 != last.0 {
+            controller.connected_changed(ctxt).await?;
+        }
+        if current.1 != last.1 {
+            controller.current_input_changed(ctxt).await?;
+        }
+        if current.2 != last.2 {
+            controller.volume_changed(ctxt).await?;
+        }
+        if current.3 != last.3 {
+            controller.bass_changed(ctxt).await?;
+        }
+        last = current;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command as Process, Stdio};
+
+    use z407::{Config, Speakers};
+
+    use super::*;
+
+    /// A private bus, stopped on drop.
+    struct Bus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl Bus {
+        /// Panics where `dbus-daemon` can't be run, rather than letting the test pass unchecked.
+        fn start() -> Bus {
+            let mut daemon = Process::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address=1"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap_or_else(|e| panic!("these tests need dbus-daemon on the PATH: {}", e));
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap()).read_line(&mut address).expect("dbus-daemon printed no address");
+            Bus { daemon, address: address.trim().to_string() }
+        }
+    }
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    async fn call(conn: &Connection, method: &str, body: &(impl serde::Serialize + zbus::zvariant::DynamicType)) -> zbus::Result<()> {
+        conn.call_method(Some(BUS_NAME), OBJECT_PATH, Some(BUS_NAME), method, body).await.map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn serves_the_controller_and_guards_destructive_calls() {
+        let bus = Bus::start();
+        let config = Config::default();
+        let speakers = Speakers::simulate(&config, &config.speakers());
+        let fleet = speakers.fleet();
+        let service = tokio::spawn(serve(connection::Builder::address(bus.address.as_str()).unwrap(), fleet.clone()));
+        let client = connection::Builder::address(bus.address.as_str()).unwrap().build().await.unwrap();
+
+        let connected = || fleet.iter().all(|(_, ctl)| ctl.state.lock().unwrap().connected);
+        let mut answered = false;
+        for _ in 0..200 {
+            if connected() && call(&client, "SwitchInput", &("aux",)).await.is_ok() {
+                answered = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(25)).await;
+        }
+        assert!(answered, "service never answered SwitchInput");
+
+        for method in ["EnterPairing", "FactoryReset"] {
+            match call(&client, method, &(false,)).await {
+                Err(zbus::Error::MethodError(name, _, _)) => assert_eq!(name.as_str(), "org.freedesktop.DBus.Error.InvalidArgs"),
+                other => panic!("{} without confirm returned {:?}", method, other),
+            }
+        }
+        let sent = |command| fleet.iter().any(|(_, ctl)| ctl.state.lock().unwrap().last_response == Some(Command::confirmation(command)));
+        assert!(!sent(Command::FactoryReset));
+        call(&client, "FactoryReset", &(true,)).await.unwrap();
+        assert!(sent(Command::FactoryReset));
+        assert!(call(&client, "PlayChime", &(4u8,)).await.is_err());
+        service.abort();
+    }
+}
@@ -0,0 +1,390 @@
+//! Optional REST API. Every route maps onto a `DaemonRequest` and answers with the same JSON as
+//! the socket protocol. `/events` is a WebSocket pushing each `Event` as a JSON text message.
+//! Any route takes `?speaker=NAME` to pick a speaker or group; without it, every speaker.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::Result;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::Deserialize;
+use serde_json::Value;
+use z407::ipc::{DaemonRequest, DaemonResponse, StateSnapshot};
+use z407::speakers::FleetEvents;
+use z407::config::parse_duration;
+use z407::{Command, Easing, Fleet, Input, LevelKind, Z407Controller};
+
+use crate::daemon::execute_on;
+
+const DEFAULT_FADE: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize)]
+struct Select {
+    speaker: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Steps {
+    steps: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct Target {
+    target: u8,
+}
+
+#[derive(Deserialize)]
+struct FadeQuery {
+    target: u8,
+    /// `10s`, `5m` or `1h`; 10s when absent.
+    over: Option<String>,
+    #[serde(default)]
+    easing: Easing,
+}
+
+#[derive(Deserialize)]
+struct Confirm {
+    #[serde(default)]
+    confirm: bool,
+}
+
+async fn run(fleet: &Fleet, select: Select, request: DaemonRequest) -> Response {
+    let targets = match fleet.resolve(select.speaker.as_deref()) {
+        Ok(targets) => targets,
+        Err(e) => return (StatusCode::NOT_FOUND, Json(DaemonResponse::error(None, e))).into_response(),
+    };
+    // What `[limits]` would refuse is answered before anything is sent.
+    let refused = targets.iter().find_map(|(_, ctl)| {
+        let s = ctl.state.lock().unwrap();
+        match request {
+            DaemonRequest::Command { command, .. } => s.check_limits(command).err(),
+            DaemonRequest::SetLevel { level, target } | DaemonRequest::Fade { level, target, .. } => {
+                s.level(level).check_target(level, target).err()
+            }
+            DaemonRequest::FadeIn { target, .. } => s.volume.check_target(LevelKind::Volume, target).err(),
+            _ => None,
+        }
+    });
+    if let Some(e) = refused {
+        return (StatusCode::CONFLICT, Json(DaemonResponse::error(None, e))).into_response();
+    }
+    let response = execute_on(fleet, select.speaker.as_deref(), request).await;
+    let status = if response.ok {
+        StatusCode::OK
+    } else if response.connected() {
+        StatusCode::GATEWAY_TIMEOUT
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(response)).into_response()
+}
+
+async fn run_command(fleet: &Fleet, select: Select, command: Command) -> Response {
+    run(fleet, select, DaemonRequest::Command { command, steps: None }).await
+}
+
+/// Pairing and factory reset drop the current link or wipe settings, so they need `?confirm=true`.
+async fn run_confirmed(fleet: &Fleet, select: Select, command: Command, confirm: Confirm) -> Response {
+    if !confirm.confirm {
+        let error = format!("{:?} requires ?confirm=true", command);
+        return (StatusCode::BAD_REQUEST, Json(DaemonResponse::error(None, error))).into_response();
+    }
+    run_command(fleet, select, command).await
+}
+
+/// One speaker's snapshot, or a map of them by name when several are selected.
+async fn state(State(fleet): State<Fleet>, Query(select): Query<Select>) -> Response {
+    let targets = match fleet.resolve(select.speaker.as_deref()) {
+        Ok(targets) => targets,
+        Err(e) => return (StatusCode::NOT_FOUND, Json(DaemonResponse::error(None, e))).into_response(),
+    };
+    let snapshot = |ctl: &Z407Controller| StateSnapshot::from(&*ctl.state.lock().unwrap());
+    if let [(_, ctl)] = targets[..] {
+        return Json(snapshot(ctl)).into_response();
+    }
+    let states: BTreeMap<&str, StateSnapshot> = targets.iter().map(|(name, ctl)| (*name, snapshot(ctl))).collect();
+    Json(states).into_response()
+}
+
+async fn level_up(State(fleet): State<Fleet>, Query(select): Query<Select>, Path(level): Path<LevelKind>, Query(q): Query<Steps>) -> Response {
+    run(&fleet, select, DaemonRequest::Command { command: level.up(), steps: q.steps }).await
+}
+
+async fn level_down(State(fleet): State<Fleet>, Query(select): Query<Select>, Path(level): Path<LevelKind>, Query(q): Query<Steps>) -> Response {
+    run(&fleet, select, DaemonRequest::Command { command: level.down(), steps: q.steps }).await
+}
+
+async fn level_set(State(fleet): State<Fleet>, Query(select): Query<Select>, Path(level): Path<LevelKind>, Query(q): Query<Target>) -> Response {
+    run(&fleet, select, DaemonRequest::SetLevel { level, target: q.target }).await
+}
+
+async fn level_fade(State(fleet): State<Fleet>, Query(select): Query<Select>, Path(level): Path<LevelKind>, Query(q): Query<FadeQuery>) -> Response {
+    let over = match q.over.as_deref().map(parse_duration).transpose() {
+        Ok(over) => over.unwrap_or(DEFAULT_FADE),
+        Err(e) => return (StatusCode::BAD_REQUEST, Json(DaemonResponse::error(None, e))).into_response(),
+    };
+    let request = DaemonRequest::Fade { level, target: q.target, over_ms: over.as_millis() as u64, easing: q.easing };
+    run(&fleet, select, request).await
+}
+
+async fn level_calibrate(State(fleet): State<Fleet>, Query(select): Query<Select>, Path(level): Path<LevelKind>) -> Response {
+    run(&fleet, select, DaemonRequest::Calibrate { level }).await
+}
+
+async fn input(State(fleet): State<Fleet>, Query(select): Query<Select>, Path(input): Path<Input>) -> Response {
+    run_command(&fleet, select, input.command()).await
+}
+
+async fn play_pause(State(fleet): State<Fleet>, Query(select): Query<Select>) -> Response {
+    run_command(&fleet, select, Command::PlayPause).await
+}
+
+async fn next(State(fleet): State<Fleet>, Query(select): Query<Select>) -> Response {
+    run_command(&fleet, select, Command::NextTrack).await
+}
+
+async fn prev(State(fleet): State<Fleet>, Query(select): Query<Select>) -> Response {
+    run_command(&fleet, select, Command::PrevTrack).await
+}
+
+async fn chime(State(fleet): State<Fleet>, Query(select): Query<Select>, Path(number): Path<u8>) -> Response {
+    let command = match number {
+        1 => Command::Sound1,
+        2 => Command::Sound2,
+        3 => Command::Sound3,
+        _ => {
+            let error = DaemonResponse::error(None, "Chime must be 1, 2 or 3");
+            return (StatusCode::NOT_FOUND, Json(error)).into_response();
+        }
+    };
+    run_command(&fleet, select, command).await
+}
+
+async fn preset(State(fleet): State<Fleet>, Query(select): Query<Select>, Path(name): Path<String>) -> Response {
+    if let Err(e) = fleet.preset(&name) {
+        return (StatusCode::NOT_FOUND, Json(DaemonResponse::error(None, e))).into_response();
+    }
+    run(&fleet, select, DaemonRequest::Preset { name }).await
+}
+
+async fn cancel(State(fleet): State<Fleet>, Query(select): Query<Select>) -> Response {
+    run(&fleet, select, DaemonRequest::Cancel).await
+}
+
+async fn pairing(State(fleet): State<Fleet>, Query(select): Query<Select>, Query(q): Query<Confirm>) -> Response {
+    run_confirmed(&fleet, select, Command::Pairing, q).await
+}
+
+async fn factory_reset(State(fleet): State<Fleet>, Query(select): Query<Select>, Query(q): Query<Confirm>) -> Response {
+    run_confirmed(&fleet, select, Command::FactoryReset, q).await
+}
+
+async fn events(State(fleet): State<Fleet>, Query(select): Query<Select>, ws: WebSocketUpgrade) -> Response {
+    let events = match fleet.subscribe(select.speaker.as_deref()) {
+        Ok(events) => events,
+        Err(e) => return (StatusCode::NOT_FOUND, Json(DaemonResponse::error(None, e))).into_response(),
+    };
+    ws.on_upgrade(move |socket| stream_events(socket, events))
+}
+
+/// Each event gains a `speaker` field naming where it came from.
+async fn stream_events(mut socket: WebSocket, mut events: FleetEvents) {
+    while let Some((speaker, event)) = events.recv().await {
+        let Ok(Value::Object(mut json)) = serde_json::to_value(&event) else { continue };
+        json.insert("speaker".to_string(), Value::String(speaker));
+        if socket.send(Message::Text(Value::Object(json).to_string())).await.is_err() {
+            break;
+        }
+    }
+}
+
+fn router(fleet: Fleet) -> Router {
+    Router::new()
+        .route("/state", get(state))
+        .route("/events", get(events))
+        .route("/:level/up", post(level_up))
+        .route("/:level/down", post(level_down))
+        .route("/:level/set", post(level_set))
+        .route("/:level/fade", post(level_fade))
+        .route("/:level/calibrate", post(level_calibrate))
+        .route("/input/:input", post(input))
+        .route("/media/play-pause", post(play_pause))
+        .route("/media/next", post(next))
+        .route("/media/prev", post(prev))
+        .route("/chime/:number", post(chime))
+        .route("/preset/:name", post(preset))
+        .route("/cancel", post(cancel))
+        .route("/pairing", post(pairing))
+        .route("/factory-reset", post(factory_reset))
+        .with_state(fleet)
+}
+
+pub async fn serve(addr: SocketAddr, fleet: Fleet) -> Result<()> {
+    let app = router(fleet);
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    println!("HTTP API on http://{}", addr);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::{to_bytes, Body};
+    use axum::http::{Method, Request};
+    use futures_util::StreamExt;
+    use tokio_tungstenite::tungstenite::Message as Frame;
+    use tower::ServiceExt;
+    use z407::{Config, Response as Z407Response, Speakers};
+
+    use super::*;
+
+    const CONFIG: &str = r#"
+[timing]
+poll_interval = "1ms"
+idle_poll_interval = "1ms"
+
+[limits]
+max_volume = "50%"
+
+[[speaker]]
+name = "kitchen"
+address = "kitchen"
+
+[[speaker]]
+name = "lounge"
+address = "lounge"
+
+[groups]
+downstairs = ["kitchen", "lounge"]
+"#;
+
+    /// Two simulated speakers, both connected, and the API in front of them.
+    async fn simulated() -> (Speakers, Fleet, Router) {
+        let config = Config::parse(CONFIG).unwrap();
+        let speakers = Speakers::simulate(&config, &config.speakers());
+        let fleet = speakers.fleet();
+        for _ in 0..200 {
+            if fleet.iter().all(|(_, ctl)| ctl.state.lock().unwrap().connected) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let app = router(fleet.clone());
+        (speakers, fleet, app)
+    }
+
+    /// The status and JSON body, or the body as a string where axum answers in plain text.
+    async fn request(app: &Router, method: Method, uri: &str) -> (StatusCode, Value) {
+        let request = Request::builder().method(method).uri(uri).body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = serde_json::from_slice(&body).unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&body).into_owned()));
+        (status, body)
+    }
+
+    #[tokio::test]
+    async fn reports_the_state_of_what_is_selected() {
+        let (_speakers, _fleet, app) = simulated().await;
+        let (status, kitchen) = request(&app, Method::GET, "/state?speaker=kitchen").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(kitchen["connected"], true);
+        assert_eq!(kitchen["volume"]["limit"], 25);
+
+        for uri in ["/state", "/state?speaker=downstairs", "/state?speaker=all"] {
+            let (status, states) = request(&app, Method::GET, uri).await;
+            assert_eq!(status, StatusCode::OK);
+            let names: Vec<&String> = states.as_object().unwrap().keys().collect();
+            assert_eq!(names, ["kitchen", "lounge"], "{}", uri);
+        }
+
+        let (status, error) = request(&app, Method::GET, "/state?speaker=attic").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let error = error["error"].as_str().unwrap();
+        assert!(error.contains("known: kitchen, lounge, downstairs, all"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn steps_and_sets_levels() {
+        let (_speakers, _fleet, app) = simulated().await;
+        let steps = [("/bass/set?speaker=kitchen&target=5", 5), ("/bass/up?speaker=kitchen&steps=2", 7), ("/bass/down?speaker=kitchen", 6)];
+        for (uri, steps) in steps {
+            let (status, response) = request(&app, Method::POST, uri).await;
+            assert_eq!(status, StatusCode::OK, "{}: {}", uri, response);
+            let (_, kitchen) = request(&app, Method::GET, "/state?speaker=kitchen").await;
+            assert_eq!(kitchen["bass"]["steps"], steps, "{}", uri);
+        }
+        let (_, lounge) = request(&app, Method::GET, "/state?speaker=lounge").await;
+        assert_eq!(lounge["bass"]["steps"], Value::Null);
+
+        let (status, _) = request(&app, Method::POST, "/treble/up").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn asks_to_confirm_pairing_and_factory_reset() {
+        let (_speakers, fleet, app) = simulated().await;
+        let kitchen = fleet.get("kitchen").unwrap();
+        for (uri, confirmed) in [("/pairing", Z407Response::Pairing), ("/factory-reset", Z407Response::FactoryReset)] {
+            for refused in [uri.to_string(), format!("{}?speaker=kitchen&confirm=false", uri)] {
+                let (status, error) = request(&app, Method::POST, &refused).await;
+                assert_eq!(status, StatusCode::BAD_REQUEST);
+                assert!(error["error"].as_str().unwrap().contains("?confirm=true"), "{}", error);
+                assert_ne!(kitchen.state.lock().unwrap().last_response.as_ref(), Some(&confirmed));
+            }
+            let (status, _) = request(&app, Method::POST, &format!("{}?speaker=kitchen&confirm=true", uri)).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(kitchen.state.lock().unwrap().last_response.as_ref(), Some(&confirmed));
+        }
+    }
+
+    #[tokio::test]
+    async fn answers_conflict_at_a_limit() {
+        let (_speakers, _fleet, app) = simulated().await;
+        // Limited but not yet calibrated, so a single step up is refused too.
+        for uri in ["/volume/up", "/volume/set?target=30", "/volume/fade?target=40&over=1s"] {
+            let (status, response) = request(&app, Method::POST, uri).await;
+            assert_eq!(status, StatusCode::CONFLICT, "{}: {}", uri, response);
+        }
+        let (status, _) = request(&app, Method::POST, "/volume/set?speaker=kitchen&target=25").await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, error) = request(&app, Method::POST, "/volume/up?speaker=kitchen").await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(error["error"].as_str().unwrap().contains("limit"), "{}", error);
+        let (_, kitchen) = request(&app, Method::GET, "/state?speaker=kitchen").await;
+        assert_eq!(kitchen["volume"]["steps"], 25);
+    }
+
+    #[tokio::test]
+    async fn streams_the_selected_speakers_events() {
+        let (_speakers, fleet, app) = simulated().await;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move { axum::serve(listener, app).await });
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/events?speaker=kitchen", addr)).await.unwrap();
+
+        fleet.get("lounge").unwrap().execute(Command::SwitchAux).await.unwrap();
+        fleet.get("kitchen").unwrap().execute(Command::SwitchUsb).await.unwrap();
+        let switched = serde_json::to_value(Z407Response::SwitchUsb).unwrap();
+        let frame = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(frame) = socket.next().await {
+                let Frame::Text(text) = frame.unwrap() else { continue };
+                let event: Value = serde_json::from_str(&text).unwrap();
+                if event["event"] == "response" {
+                    return event;
+                }
+            }
+            panic!("event stream closed");
+        });
+        let event = frame.await.expect("no event frame arrived");
+        assert_eq!(event["speaker"], "kitchen");
+        assert_eq!(event["response"], switched);
+        server.abort();
+    }
+}
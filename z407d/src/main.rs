@@ -0,0 +1,23 @@
+#[cfg(all(target_os = "linux", any(feature = "mpris", feature = "dbus")))]
+mod bus;
+#[cfg(unix)]
+mod daemon;
+#[cfg(all(target_os = "linux", feature = "dbus"))]
+mod dbus;
+#[cfg(all(unix, feature = "http"))]
+mod http;
+#[cfg(all(target_os = "linux", feature = "mpris"))]
+mod mpris;
+#[cfg(all(unix, feature = "mqtt"))]
+mod mqtt;
+
+#[cfg(unix)]
+fn main() -> anyhow::Result<()> {
+    daemon::main()
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("z407d serves a Unix domain socket and only runs on Unix-like systems");
+    std::process::exit(1);
+}
@@ -0,0 +1,226 @@
+//! Optional MPRIS2 player on the session bus, so desktop media keys and widgets drive the speaker.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::interval;
+use z407::{Command, Z407Controller};
+use zbus::zvariant::{ObjectPath, OwnedValue};
+use zbus::{connection, fdo, interface};
+
+use crate::bus;
+
+const BUS_NAME: &str = "org.mpris.MediaPlayer2.z407";
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+
+struct Root {
+    identity: String,
+}
+
+#[interface(name = "org.mpris.MediaPlayer2")]
+impl Root {
+    fn raise(&self) {}
+
+    fn quit(&self) {}
+
+    #[zbus(property)]
+    fn can_quit(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_raise(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn identity(&self) -> &str {
+        &self.identity
+    }
+
+    #[zbus(property)]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    #[zbus(property)]
+    fn supported_mime_types(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+struct Player {
+    ctl: Z407Controller,
+}
+
+impl Player {
+    async fn run(&self, cmd: Command) -> fdo::Result<()> {
+        self.ctl.execute(cmd).await.map_err(|e| fdo::Error::Failed(e.to_string()))
+    }
+
+    /// MPRIS volume is 0.0–1.0; 0.0 until the volume has been calibrated.
+    fn current_volume(&self) -> f64 {
+        let s = self.ctl.state.lock().unwrap();
+        s.volume.percent().map_or(0.0, |p| p as f64 / 100.0)
+    }
+}
+
+/// The speaker only has a play/pause toggle, so Play, Pause and PlayPause all send it.
+#[interface(name = "org.mpris.MediaPlayer2.Player")]
+impl Player {
+    async fn next(&self) -> fdo::Result<()> {
+        self.run(Command::NextTrack).await
+    }
+
+    async fn previous(&self) -> fdo::Result<()> {
+        self.run(Command::PrevTrack).await
+    }
+
+    async fn play_pause(&self) -> fdo::Result<()> {
+        self.run(Command::PlayPause).await
+    }
+
+    async fn play(&self) -> fdo::Result<()> {
+        self.run(Command::PlayPause).await
+    }
+
+    async fn pause(&self) -> fdo::Result<()> {
+        self.run(Command::PlayPause).await
+    }
+
+    fn stop(&self) -> fdo::Result<()> {
+        Err(fdo::Error::NotSupported("The Z407 has no stop command".to_string()))
+    }
+
+    fn seek(&self, _offset: i64) {}
+
+    fn set_position(&self, _track_id: ObjectPath<'_>, _position: i64) {}
+
+    fn open_uri(&self, _uri: &str) -> fdo::Result<()> {
+        Err(fdo::Error::NotSupported("The Z407 cannot open URIs".to_string()))
+    }
+
+    /// The speaker doesn't report whether the source is playing.
+    #[zbus(property)]
+    fn playback_status(&self) -> &str {
+        "Playing"
+    }
+
+    #[zbus(property)]
+    fn rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn set_rate(&self, _rate: f64) {}
+
+    #[zbus(property)]
+    fn minimum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn maximum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn metadata(&self) -> HashMap<String, OwnedValue> {
+        HashMap::new()
+    }
+
+    #[zbus(property)]
+    fn volume(&self) -> f64 {
+        self.current_volume()
+    }
+
+    /// Goes through absolute stepping, so a slider drag becomes one `set_volume` that supersedes
+    /// any still running. Anything past the volume limit is taken as the limit.
+    #[zbus(property)]
+    fn set_volume(&mut self, volume: f64) {
+        let level = self.ctl.state.lock().unwrap().volume;
+        let target = ((volume.clamp(0.0, 1.0) * level.max_steps as f64).round() as u8).min(level.ceiling());
+        let ctl = self.ctl.clone();
+        tokio::spawn(async move {
+            if let Err(e) = ctl.set_volume(target).await {
+                eprintln!("MPRIS volume change failed: {}", e);
+            }
+        });
+    }
+
+    #[zbus(property)]
+    fn position(&self) -> i64 {
+        0
+    }
+
+    #[zbus(property)]
+    fn can_go_next(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_go_previous(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_play(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_pause(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_seek(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
+
+/// With several speakers each registers its own player, e.g. `org.mpris.MediaPlayer2.z407.kitchen`.
+pub async fn run(ctl: Z407Controller, instance: Option<String>) -> Result<()> {
+    let (bus_name, identity) = match &instance {
+        Some(name) => (format!("{}.{}", BUS_NAME, bus::element(name)), format!("Logitech Z407 ({})", name)),
+        None => (BUS_NAME.to_string(), "Logitech Z407".to_string()),
+    };
+    let mut events = ctl.subscribe();
+    let conn = connection::Builder::session()?
+        .name(bus_name.as_str())?
+        .serve_at(OBJECT_PATH, Root { identity })?
+        .serve_at(OBJECT_PATH, Player { ctl })?
+        .build()
+        .await?;
+    println!("MPRIS player registered as {}", bus_name);
+
+    let player = conn.object_server().interface::<_, Player>(OBJECT_PATH).await?;
+    let mut last_volume = player.get().await.current_volume();
+    // Calibration moves the level without a notification of its own, so poll as well.
+    let mut refresh = interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            event = events.recv() => if let Err(RecvError::Closed) = event {
+                return Ok(());
+            },
+            _ = refresh.tick() => {}
+        }
+        let volume = player.get().await.current_volume();
+        if volume != last_volume {
+            last_volume = volume;
+            player.get().await.volume_changed(player.signal_context()).await?;
+        }
+    }
+}
@@ -0,0 +1,414 @@
+//! Optional MQTT bridge. Publishes the speaker state as retained JSON, accepts commands on
+//! `<topic>/command` and `<topic>/<volume|bass|input|preset>/set`, and announces itself to Home
+//! Assistant through MQTT discovery.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use rumqttc::{AsyncClient, Event as MqttEvent, LastWill, MqttOptions, Packet, Publish, QoS};
+use serde_json::{json, Value};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{interval, sleep};
+use z407::ipc::{DaemonRequest, StateSnapshot};
+use z407::{Command, Fleet, Input, LevelKind, Z407Controller};
+
+use crate::daemon::execute;
+
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    /// Base topic, also used as the Home Assistant node id.
+    pub topic: String,
+    pub discovery_prefix: String,
+    /// Set when bridging one of several speakers, to tell their Home Assistant devices apart.
+    pub speaker: Option<String>,
+}
+
+const DEFAULT_PORT: u16 = 1883;
+
+impl MqttConfig {
+    /// Splits `host`, `host:port`, `[v6]` or `[v6]:port`; an unbracketed IPv6 address is taken
+    /// as a host without a port. The host comes back without brackets.
+    pub fn parse_broker(broker: &str) -> Result<(String, u16)> {
+        let port = |port: &str| port.parse().map_err(|_| anyhow!("Invalid MQTT port in {:?}", broker));
+        if let Some(rest) = broker.strip_prefix('[') {
+            let (host, after) = rest.split_once(']').ok_or_else(|| anyhow!("Missing ] in MQTT broker {:?}", broker))?;
+            return match after {
+                "" => Ok((host.to_string(), DEFAULT_PORT)),
+                after => match after.strip_prefix(':') {
+                    Some(p) => Ok((host.to_string(), port(p)?)),
+                    None => Err(anyhow!("Invalid MQTT broker {:?}", broker)),
+                },
+            };
+        }
+        match broker.split_once(':') {
+            Some((host, p)) if !p.contains(':') => Ok((host.to_string(), port(p)?)),
+            _ => Ok((broker.to_string(), DEFAULT_PORT)),
+        }
+    }
+
+    /// The host as rumqttc wants it, which joins it to the port with a `:`.
+    fn address(&self) -> String {
+        match self.host.contains(':') {
+            true => format!("[{}]", self.host),
+            false => self.host.clone(),
+        }
+    }
+}
+
+/// Discovery node ids may not contain `/`, which per-speaker topics do.
+fn node_id(config: &MqttConfig) -> String {
+    config.topic.replace('/', "_")
+}
+
+fn availability_topic(config: &MqttConfig) -> String {
+    format!("{}/availability", config.topic)
+}
+
+fn state_topic(config: &MqttConfig) -> String {
+    format!("{}/state", config.topic)
+}
+
+/// Home Assistant's MQTT integration has no media_player platform, so the media keys are
+/// exposed as buttons next to the input select and the volume/bass numbers.
+fn discovery(config: &MqttConfig, state: &StateSnapshot, presets: &[&str]) -> Vec<(String, Value)> {
+    let base = &config.topic;
+    let node = node_id(config);
+    let name = match &config.speaker {
+        Some(speaker) => format!("Logitech Z407 ({})", speaker),
+        None => "Logitech Z407".to_string(),
+    };
+    let device = json!({
+        "identifiers": [node],
+        "name": name,
+        "manufacturer": "Logitech",
+        "model": "Z407",
+    });
+    let entity = |component: &str, object: &str, name: &str, extra: Value| {
+        let mut payload = json!({
+            "name": name,
+            "unique_id": format!("{}_{}", node, object),
+            "availability_topic": availability_topic(config),
+            "device": device,
+        });
+        if let (Value::Object(payload), Value::Object(extra)) = (&mut payload, extra) {
+            payload.extend(extra);
+        }
+        let topic = format!("{}/{}/{}/{}/config", config.discovery_prefix, component, node, object);
+        (topic, payload)
+    };
+    let level = |kind: LevelKind, max: u8| {
+        let key = kind.to_string().to_lowercase();
+        entity("number", &key, &kind.to_string(), json!({
+            "state_topic": state_topic(config),
+            "value_template": format!("{{{{ value_json.{}.steps }}}}", key),
+            "command_topic": format!("{}/{}/set", base, key),
+            "min": 0,
+            "max": max,
+            "step": 1,
+        }))
+    };
+    let button = |command: Command, name: &str| {
+        let payload = serde_json::to_value(command).unwrap_or_default();
+        let object = payload.as_str().unwrap_or_default().to_string();
+        entity("button", &object, name, json!({
+            "command_topic": format!("{}/command", base),
+            "payload_press": payload,
+        }))
+    };
+
+    let mut entities = vec![
+        entity("binary_sensor", "connected", "Connected", json!({
+            "state_topic": state_topic(config),
+            "value_template": "{{ 'ON' if value_json.connected else 'OFF' }}",
+            "device_class": "connectivity",
+        })),
+        entity("select", "input", "Input", json!({
+            "state_topic": state_topic(config),
+            "value_template": "{{ value_json.input }}",
+            "command_topic": format!("{}/input/set", base),
+            "options": ["bluetooth", "aux", "usb"],
+        })),
+        level(LevelKind::Volume, state.volume.limit.unwrap_or(state.volume.max_steps)),
+        level(LevelKind::Bass, state.bass.limit.unwrap_or(state.bass.max_steps)),
+        button(Command::PlayPause, "Play/Pause"),
+        button(Command::NextTrack, "Next track"),
+        button(Command::PrevTrack, "Previous track"),
+    ];
+    if !presets.is_empty() {
+        entities.push(entity("select", "preset", "Preset", json!({
+            "command_topic": format!("{}/preset/set", base),
+            "options": presets,
+        })));
+    }
+    entities
+}
+
+fn parse_request(config: &MqttConfig, publish: &Publish) -> Result<DaemonRequest> {
+    let payload = std::str::from_utf8(&publish.payload)?.trim();
+    let subtopic = publish.topic.strip_prefix(&config.topic).unwrap_or_default();
+    let level = |level: LevelKind| -> Result<DaemonRequest> {
+        // Home Assistant sends numbers as floats, e.g. "12.0".
+        let target = payload.parse::<f64>().map_err(|_| anyhow!("Invalid level {:?}", payload))?;
+        Ok(DaemonRequest::SetLevel { level, target: target.round().clamp(0.0, u8::MAX as f64) as u8 })
+    };
+
+    match subtopic {
+        "/command" => {
+            let command: Command = serde_json::from_value(Value::String(payload.to_string()))
+                .map_err(|_| anyhow!("Unknown command {:?}", payload))?;
+            // Nothing on a broker can confirm, so what HTTP and D-Bus gate isn't accepted at all.
+            if matches!(command, Command::Pairing | Command::FactoryReset) || command.is_handshake() {
+                return Err(anyhow!("{} is not accepted over MQTT", payload));
+            }
+            Ok(DaemonRequest::Command { command, steps: None })
+        }
+        "/volume/set" => level(LevelKind::Volume),
+        "/bass/set" => level(LevelKind::Bass),
+        "/input/set" => {
+            let input: Input = payload.parse()?;
+            Ok(DaemonRequest::Command { command: input.command(), steps: None })
+        }
+        "/preset/set" => Ok(DaemonRequest::Preset { name: payload.to_string() }),
+        _ => Err(anyhow!("Unexpected topic {}", publish.topic)),
+    }
+}
+
+fn publish_state(client: &AsyncClient, config: &MqttConfig, state: &StateSnapshot, last: &mut Option<StateSnapshot>) {
+    if last.as_ref() == Some(state) {
+        return;
+    }
+    let Ok(payload) = serde_json::to_vec(state) else { return };
+    if client.try_publish(state_topic(config), QoS::AtLeastOnce, true, payload).is_ok() {
+        *last = Some(state.clone());
+    }
+}
+
+pub async fn run(config: MqttConfig, fleet: Fleet, ctl: Z407Controller) -> Result<()> {
+    let mut options = MqttOptions::new(format!("z407d-{}", node_id(&config)), config.address(), config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(availability_topic(&config), "offline", QoS::AtLeastOnce, true));
+    let (client, mut eventloop) = AsyncClient::new(options, 64);
+
+    let mut events = ctl.subscribe();
+    let mut last: Option<StateSnapshot> = None;
+    // Not every state change comes with an event (retry countdowns, command errors).
+    let mut refresh = interval(Duration::from_secs(1));
+    println!("MQTT bridge connecting to {}:{}", config.address(), config.port);
+
+    loop {
+        let snapshot = || StateSnapshot::from(&*ctl.state.lock().unwrap());
+        tokio::select! {
+            polled = eventloop.poll() => match polled {
+                Ok(MqttEvent::Incoming(Packet::ConnAck(_))) => {
+                    println!("MQTT connected");
+                    let state = snapshot();
+                    let presets: Vec<&str> = fleet.presets().collect();
+                    for (topic, payload) in discovery(&config, &state, &presets) {
+                        let _ = client.try_publish(topic, QoS::AtLeastOnce, true, payload.to_string());
+                    }
+                    let _ = client.try_subscribe(format!("{}/command", config.topic), QoS::AtLeastOnce);
+                    let _ = client.try_subscribe(format!("{}/+/set", config.topic), QoS::AtLeastOnce);
+                    let _ = client.try_publish(availability_topic(&config), QoS::AtLeastOnce, true, "online");
+                    last = None;
+                    publish_state(&client, &config, &state, &mut last);
+                }
+                Ok(MqttEvent::Incoming(Packet::Publish(publish))) => match parse_request(&config, &publish) {
+                    Ok(request) => {
+                        let (fleet, ctl) = (fleet.clone(), ctl.clone());
+                        tokio::spawn(async move {
+                            let response = execute(&fleet, &ctl, request).await;
+                            if let Some(error) = response.error {
+                                eprintln!("MQTT command failed: {}", error);
+                            }
+                        });
+                    }
+                    Err(e) => eprintln!("Ignoring MQTT message on {}: {}", publish.topic, e),
+                },
+                Ok(_) => {}
+                Err(e) => {
+                    eprintln!("MQTT connection error: {}", e);
+                    sleep(Duration::from_secs(5)).await;
+                }
+            },
+            event = events.recv() => match event {
+                Ok(_) | Err(RecvError::Lagged(_)) => publish_state(&client, &config, &snapshot(), &mut last),
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            _ = refresh.tick() => publish_state(&client, &config, &snapshot(), &mut last),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokio::time::timeout;
+    use z407::{Config, Event, Response, Speakers, Z407State};
+
+    use super::*;
+
+    fn config(speaker: Option<&str>) -> MqttConfig {
+        MqttConfig {
+            host: "localhost".to_string(),
+            port: DEFAULT_PORT,
+            topic: match speaker {
+                Some(speaker) => format!("z407/{}", speaker),
+                None => "z407".to_string(),
+            },
+            discovery_prefix: "homeassistant".to_string(),
+            speaker: speaker.map(str::to_string),
+        }
+    }
+
+    fn request(topic: &str, payload: &str) -> Result<DaemonRequest> {
+        parse_request(&config(None), &Publish::new(topic, QoS::AtLeastOnce, payload))
+    }
+
+    #[test]
+    fn parses_brokers() {
+        let parse = |broker| MqttConfig::parse_broker(broker).ok();
+        assert_eq!(parse("localhost"), Some(("localhost".to_string(), 1883)));
+        assert_eq!(parse("10.0.0.2:1884"), Some(("10.0.0.2".to_string(), 1884)));
+        assert_eq!(parse("::1"), Some(("::1".to_string(), 1883)));
+        assert_eq!(parse("fe80::1:2"), Some(("fe80::1:2".to_string(), 1883)));
+        assert_eq!(parse("[::1]"), Some(("::1".to_string(), 1883)));
+        assert_eq!(parse("[::1]:8883"), Some(("::1".to_string(), 8883)));
+        assert_eq!(parse("broker:mqtt"), None);
+        assert_eq!(parse("[::1"), None);
+        assert_eq!(parse("[::1]8883"), None);
+    }
+
+    #[test]
+    fn brackets_ipv6_hosts_for_the_client() {
+        let mut config = config(None);
+        config.host = "::1".to_string();
+        assert_eq!(config.address(), "[::1]");
+        config.host = "broker.lan".to_string();
+        assert_eq!(config.address(), "broker.lan");
+    }
+
+    #[test]
+    fn announces_every_entity() {
+        let state = StateSnapshot::from(&Z407State::default());
+        let entities = discovery(&config(None), &state, &[]);
+        let topics: Vec<&str> = entities.iter().map(|(topic, _)| topic.as_str()).collect();
+        assert_eq!(topics, [
+            "homeassistant/binary_sensor/z407/connected/config",
+            "homeassistant/select/z407/input/config",
+            "homeassistant/number/z407/volume/config",
+            "homeassistant/number/z407/bass/config",
+            "homeassistant/button/z407/play_pause/config",
+            "homeassistant/button/z407/next_track/config",
+            "homeassistant/button/z407/prev_track/config",
+        ]);
+        let (_, volume) = &entities[2];
+        assert_eq!(volume["command_topic"], "z407/volume/set");
+        assert_eq!(volume["max"], state.volume.max_steps);
+        assert_eq!(volume["unique_id"], "z407_volume");
+        assert_eq!(volume["availability_topic"], "z407/availability");
+        let (_, play) = &entities[4];
+        assert_eq!(play["payload_press"], "play_pause");
+    }
+
+    #[test]
+    fn caps_numbers_at_the_limit_and_names_each_speaker() {
+        let mut state = Z407State::default();
+        state.volume.limit = Some(30);
+        let entities = discovery(&config(Some("kitchen")), &StateSnapshot::from(&state), &["movie", "quiet"]);
+        let (topic, volume) = &entities[2];
+        assert_eq!(topic, "homeassistant/number/z407_kitchen/volume/config");
+        assert_eq!(volume["max"], 30);
+        assert_eq!(volume["device"]["name"], "Logitech Z407 (kitchen)");
+        let (topic, preset) = entities.last().unwrap();
+        assert_eq!(topic, "homeassistant/select/z407_kitchen/preset/config");
+        assert_eq!(preset["options"], json!(["movie", "quiet"]));
+    }
+
+    #[test]
+    fn maps_command_topics() {
+        let command = |command| Some(DaemonRequest::Command { command, steps: None });
+        assert_eq!(request("z407/command", "play_pause").ok(), command(Command::PlayPause));
+        assert_eq!(request("z407/command", " volume_up\n").ok(), command(Command::VolumeUp));
+        assert_eq!(request("z407/input/set", "aux").ok(), command(Command::SwitchAux));
+        let set = |level, target| Some(DaemonRequest::SetLevel { level, target });
+        assert_eq!(request("z407/volume/set", "12.0").ok(), set(LevelKind::Volume, 12));
+        assert_eq!(request("z407/bass/set", "7").ok(), set(LevelKind::Bass, 7));
+        let preset = Some(DaemonRequest::Preset { name: "movie".to_string() });
+        assert_eq!(request("z407/preset/set", "movie").ok(), preset);
+    }
+
+    #[test]
+    fn rejects_unknown_and_unsafe_commands() {
+        for command in ["factory_reset", "pairing", "initiate", "acknowledge"] {
+            assert!(request("z407/command", command).is_err(), "{}", command);
+        }
+        assert!(request("z407/command", "warp").is_err());
+        assert!(request("z407/volume/set", "loud").is_err());
+        assert!(request("z407/input/set", "hdmi").is_err());
+        assert!(request("z407/other", "play_pause").is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs an MQTT broker at $Z407_TEST_MQTT, by default localhost:1883"]
+    async fn bridges_a_speaker_through_a_broker() {
+        let broker = std::env::var("Z407_TEST_MQTT").unwrap_or_else(|_| "localhost".to_string());
+        let (host, port) = MqttConfig::parse_broker(&broker).unwrap();
+        let topic = format!("z407-test-{}", std::process::id());
+        let config = MqttConfig { host, port, topic: topic.clone(), discovery_prefix: format!("{}-ha", topic), speaker: None };
+
+        let (observer, mut observed) = AsyncClient::new(MqttOptions::new(format!("{}-observer", topic), config.address(), config.port), 64);
+        observer.subscribe(format!("{}/#", topic), QoS::AtLeastOnce).await.unwrap();
+        observer.subscribe(format!("{}/#", config.discovery_prefix), QoS::AtLeastOnce).await.unwrap();
+        let volume_config = format!("{}/number/{}/volume/config", config.discovery_prefix, topic);
+
+        let simulated = Config::default();
+        let speakers = Speakers::simulate(&simulated, &simulated.speakers());
+        let fleet = speakers.fleet();
+        let ctl = fleet.iter().next().unwrap().1.clone();
+        let mut events = ctl.subscribe();
+        let bridge = tokio::spawn(run(config, fleet.clone(), ctl.clone()));
+
+        // Every retained topic, as last published.
+        let mut seen: HashMap<String, String> = HashMap::new();
+        let connected = |seen: &HashMap<String, String>| {
+            let state = seen.get(&format!("{}/state", topic)).and_then(|s| serde_json::from_str::<Value>(s).ok());
+            seen.contains_key(&volume_config)
+                && seen.get(&format!("{}/availability", topic)).map(String::as_str) == Some("online")
+                && state.is_some_and(|state| state["connected"] == true)
+        };
+        let announced = timeout(Duration::from_secs(10), async {
+            while !connected(&seen) {
+                if let MqttEvent::Incoming(Packet::Publish(p)) = observed.poll().await.unwrap() {
+                    seen.insert(p.topic.clone(), String::from_utf8_lossy(&p.payload).into_owned());
+                }
+            }
+        });
+        assert!(announced.await.is_ok(), "bridge never announced a connected speaker: {:?}", seen);
+        let volume: Value = serde_json::from_str(&seen[&volume_config]).unwrap();
+        assert_eq!(volume["command_topic"], format!("{}/volume/set", topic));
+
+        // Pairing is dropped; the play/pause behind it still gets through.
+        for payload in ["pairing", "play_pause"] {
+            observer.publish(format!("{}/command", topic), QoS::AtLeastOnce, false, payload).await.unwrap();
+        }
+        let mut responses = Vec::new();
+        let played = timeout(Duration::from_secs(10), async {
+            loop {
+                tokio::select! {
+                    polled = observed.poll() => { polled.unwrap(); }
+                    event = events.recv() => if let Ok(Event::Response { response, .. }) = event {
+                        responses.push(response.clone());
+                        if response == Response::PlayPause {
+                            break;
+                        }
+                    },
+                }
+            }
+        });
+        assert!(played.await.is_ok(), "play_pause never reached the speaker");
+        assert!(!responses.contains(&Response::Pairing));
+        bridge.abort();
+    }
+}